@@ -0,0 +1,95 @@
+use rayon::prelude::*;
+
+use super::Cache;
+use super::Dict;
+use super::LexerToken;
+use super::TokenizeError;
+
+impl Dict {
+    /// Tokenizes many texts in parallel on rayon's thread pool. Results are in the same order as the texts.
+    ///
+    /// Each worker reuses one [`Cache`] across the texts it handles. To use a different thread pool, call this from inside `ThreadPool::install`.
+    ///
+    /// Only available with the `parallel` feature.
+    pub fn tokenize_batch<S : AsRef<str> + Sync>(&self, texts : &[S]) -> Vec<Result<(Vec<LexerToken>, i64), TokenizeError>>
+    {
+        texts.par_iter()
+            .map_init(Cache::new, |cache, text| {
+                let mut tokens = Vec::new();
+                let cost = self.tokenize_with_cache(cache, text.as_ref(), &mut tokens)?;
+                Ok((tokens, cost))
+            })
+            .collect()
+    }
+
+    /// Like [`Dict::tokenize_batch`], but for texts from an iterator, such as lines being read from a file. Results are in the same order as the texts.
+    ///
+    /// Workers pull texts from the iterator as they need them, so the texts themselves don't all have to be in memory at once, and each one is dropped once it's tokenized. The results are all kept until the end, though, so memory use still grows with the number of texts.
+    ///
+    /// Only available with the `parallel` feature.
+    pub fn tokenize_batch_iter<S, I>(&self, texts : I) -> Vec<Result<(Vec<LexerToken>, i64), TokenizeError>>
+    where
+        S : AsRef<str> + Send,
+        I : IntoIterator<Item = S>,
+        I::IntoIter : Send,
+    {
+        let mut results : Vec<_> = texts.into_iter().enumerate()
+            .par_bridge()
+            .map_init(Cache::new, |cache, (index, text)| {
+                let mut tokens = Vec::new();
+                let result = self.tokenize_with_cache(cache, text.as_ref(), &mut tokens).map(|cost| (tokens, cost));
+                (index, result)
+            })
+            .collect();
+        // par_bridge hands out texts in whatever order the workers ask for them
+        results.sort_unstable_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::tests::test_dict;
+
+    #[test]
+    fn test_tokenize_batch()
+    {
+        let dict = test_dict();
+        let texts : Vec<String> = (0..200).map(|i| ["これを持っていけ", "飛行機 abc", "", "これ"][i % 4].repeat(i % 7 + 1)).collect();
+        let results = dict.tokenize_batch(&texts);
+        assert_eq!(results.len(), texts.len());
+        for (text, result) in texts.iter().zip(&results)
+        {
+            match (result, dict.tokenize(text))
+            {
+                (Ok((tokens, cost)), Ok((expected_tokens, expected_cost))) =>
+                {
+                    assert_eq!(*cost, expected_cost);
+                    let ranges : Vec<_> = tokens.iter().map(|token| token.range.clone()).collect();
+                    let expected_ranges : Vec<_> = expected_tokens.iter().map(|token| token.range.clone()).collect();
+                    assert_eq!(ranges, expected_ranges);
+                }
+                (Err(_), Err(_)) => {}
+                _ => panic!("batch result differs from tokenize for {:?}", text)
+            }
+        }
+
+        // owned texts from an iterator that isn't backed by a slice
+        let iter_results = dict.tokenize_batch_iter(texts.clone().into_iter().filter(|_| true));
+        assert_eq!(iter_results.len(), texts.len());
+        for (result, iter_result) in results.iter().zip(&iter_results)
+        {
+            match (result, iter_result)
+            {
+                (Ok((tokens, cost)), Ok((iter_tokens, iter_cost))) =>
+                {
+                    assert_eq!(cost, iter_cost);
+                    assert_eq!(tokens.iter().map(|token| token.range.clone()).collect::<Vec<_>>(), iter_tokens.iter().map(|token| token.range.clone()).collect::<Vec<_>>());
+                }
+                (Err(_), Err(_)) => {}
+                _ => panic!("tokenize_batch_iter result differs from tokenize_batch")
+            }
+        }
+        assert!(dict.tokenize_batch_iter(Vec::<String>::new()).is_empty());
+    }
+}
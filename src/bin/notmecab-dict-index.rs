@@ -0,0 +1,33 @@
+use std::process::exit;
+
+use notmecab::CompiledDictionary;
+
+fn main()
+{
+    let args : Vec<String> = std::env::args().collect();
+    if args.len() < 2 || args.len() > 3
+    {
+        eprintln!("usage: {} <source directory> [output directory]", args[0]);
+        eprintln!();
+        eprintln!("Compiles the *.csv, matrix.def, char.def and unk.def files of a UTF-8 mecab dictionary");
+        eprintln!("into sys.dic, matrix.bin, char.bin and unk.dic. The output directory defaults to the source directory.");
+        exit(2);
+    }
+    let source = &args[1];
+    let target = args.get(2).unwrap_or(source);
+    
+    let dict = match CompiledDictionary::compile_directory(source)
+    {
+        Ok(dict) => dict,
+        Err(err) =>
+        {
+            eprintln!("failed to compile {}: {}", source, err);
+            exit(1);
+        }
+    };
+    if let Err(err) = dict.write_to_directory(target)
+    {
+        eprintln!("failed to write to {}: {}", target, err);
+        exit(1);
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::Path;
+use std::process::exit;
+
+use notmecab::Blob;
+use notmecab::Dict;
+
+fn open(path : &Path) -> Blob
+{
+    match Blob::open(path)
+    {
+        Ok(blob) => blob,
+        Err(err) =>
+        {
+            eprintln!("failed to open {}: {}", path.display(), err);
+            exit(1);
+        }
+    }
+}
+
+fn main()
+{
+    let args : Vec<String> = std::env::args().collect();
+    if args.len() < 2 || args.len() > 3
+    {
+        eprintln!("usage: {} <dictionary directory> [user dictionary]", args[0]);
+        eprintln!();
+        eprintln!("Checks that the sys.dic, unk.dic, matrix.bin and char.bin files in a directory (and optionally");
+        eprintln!("a user dictionary) are consistent with each other. Exits with 1 if any problems are found.");
+        exit(2);
+    }
+    let directory = Path::new(&args[1]);
+    
+    let result = Dict::load(
+        open(&directory.join("sys.dic")),
+        open(&directory.join("unk.dic")),
+        open(&directory.join("matrix.bin")),
+        open(&directory.join("char.bin"))
+    );
+    let mut dict = match result
+    {
+        Ok(dict) => dict,
+        Err(err) =>
+        {
+            eprintln!("failed to load {}: {}", directory.display(), err);
+            exit(1);
+        }
+    };
+    if let Some(user_dic) = args.get(2)
+    {
+        if let Err(err) = dict.load_user_dictionary(open(Path::new(user_dic)))
+        {
+            eprintln!("failed to load {}: {}", user_dic, err);
+            exit(1);
+        }
+    }
+    
+    let problems = dict.verify();
+    for problem in &problems
+    {
+        println!("{}", problem);
+    }
+    if !problems.is_empty()
+    {
+        eprintln!("{} problem(s) found", problems.len());
+        exit(1);
+    }
+}
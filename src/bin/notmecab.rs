@@ -0,0 +1,475 @@
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+use std::process::exit;
+
+use notmecab::Blob;
+use notmecab::Dict;
+use notmecab::Features;
+use notmecab::LexerToken;
+use notmecab::NodeFormat;
+use notmecab::TokenType;
+
+const USAGE : &str = "\
+Tokenizes text line by line, like mecab. Reads the given files, or stdin if there are none.
+
+options:
+  -d, --dicdir=DIR               dictionary directory (sys.dic, plus unk.dic or unk.def, matrix.bin or
+                                 matrix.def, and char.bin or char.def)
+  -u, --userdic=FILE             user dictionary CSV file; can be given more than once
+  -O, --output-format-type=TYPE  output format: wakati, yomi, dump or chasen; default is mecab's
+                                 surface, tab, feature, with EOS after each line
+  -F, --node-format=FORMAT       mecab-style template for each token, e.g. '%m\t%f[7]\n'
+  -U, --unk-format=FORMAT        template for unknown tokens; defaults to the node format
+  -B, --bos-format=FORMAT        template for the start of each line; defaults to nothing
+  -E, --eos-format=FORMAT        template for the end of each line; defaults to 'EOS\n'
+  --no-space-stripping           tokenize 0x20 spaces instead of skipping them
+  --no-unk-forced-processing     ignore char.def's flag for always making unknown tokens
+  --no-unk-greedy-grouping       don't group runs of characters into unknown tokens
+  --no-unk-prefix-grouping       don't make unknown tokens from the prefixes of runs of characters
+  -h, --help                     show this help";
+
+// mecab's feature string for BOS and EOS nodes in IPADIC and UniDic
+const BOS_FEATURE : &str = "BOS/EOS,*,*,*,*,*,*,*,*";
+
+enum OutputFormat {
+    Default,
+    Wakati,
+    Yomi,
+    Dump,
+    Chasen,
+    Custom(NodeFormat),
+}
+
+struct Options {
+    dicdir : Option<String>,
+    userdics : Vec<String>,
+    format : OutputFormat,
+    // the templates of --node-format and friends, which override --output-format-type
+    templates : [Option<String>; 4],
+    space_stripping : bool,
+    unk_forced_processing : bool,
+    unk_greedy_grouping : bool,
+    unk_prefix_grouping : bool,
+    files : Vec<String>,
+}
+
+fn usage_error(program : &str, message : &str) -> !
+{
+    eprintln!("{}: {}", program, message);
+    eprintln!("usage: {} [options] [file...]", program);
+    eprintln!("try '{} --help' for more information", program);
+    exit(2);
+}
+
+fn parse_format(program : &str, name : &str) -> OutputFormat
+{
+    match name
+    {
+        "wakati" => OutputFormat::Wakati,
+        "yomi" => OutputFormat::Yomi,
+        "dump" => OutputFormat::Dump,
+        "chasen" => OutputFormat::Chasen,
+        _ => usage_error(program, &format!("unknown output format: {}", name))
+    }
+}
+
+fn parse_args(args : &[String]) -> Options
+{
+    let program = &args[0];
+    let mut options = Options {
+        dicdir : None,
+        userdics : Vec::new(),
+        format : OutputFormat::Default,
+        templates : [None, None, None, None],
+        space_stripping : true,
+        unk_forced_processing : true,
+        unk_greedy_grouping : true,
+        unk_prefix_grouping : true,
+        files : Vec::new(),
+    };
+    let mut args = args[1..].iter();
+    while let Some(arg) = args.next()
+    {
+        // options with values can be given as "-d DIR", "-dDIR", "--dicdir DIR" or "--dicdir=DIR"
+        let (name, inline_value) = if let Some(long) = arg.strip_prefix("--")
+        {
+            match long.find('=')
+            {
+                Some(equals) => (&arg[..equals + 2], Some(&long[equals + 1..])),
+                None => (arg.as_str(), None)
+            }
+        }
+        else if arg.len() > 2 && arg.starts_with('-')
+        {
+            (&arg[..2], Some(&arg[2..]))
+        }
+        else
+        {
+            (arg.as_str(), None)
+        };
+        let mut value = ||
+        {
+            match inline_value
+            {
+                Some(value) => value.to_string(),
+                None => match args.next()
+                {
+                    Some(value) => value.clone(),
+                    None => usage_error(program, &format!("{} needs a value", name))
+                }
+            }
+        };
+        match name
+        {
+            "-d" | "--dicdir" => options.dicdir = Some(value()),
+            "-u" | "--userdic" => options.userdics.push(value()),
+            "-O" | "--output-format-type" => options.format = parse_format(program, &value()),
+            "-F" | "--node-format" => options.templates[0] = Some(value()),
+            "-U" | "--unk-format" => options.templates[1] = Some(value()),
+            "-B" | "--bos-format" => options.templates[2] = Some(value()),
+            "-E" | "--eos-format" => options.templates[3] = Some(value()),
+            "--no-space-stripping" => options.space_stripping = false,
+            "--no-unk-forced-processing" => options.unk_forced_processing = false,
+            "--no-unk-greedy-grouping" => options.unk_greedy_grouping = false,
+            "--no-unk-prefix-grouping" => options.unk_prefix_grouping = false,
+            "-h" | "--help" =>
+            {
+                println!("usage: {} [options] [file...]", program);
+                println!();
+                println!("{}", USAGE);
+                exit(0);
+            }
+            "-" => options.files.push(arg.clone()),
+            _ if arg.starts_with('-') => usage_error(program, &format!("unknown option: {}", arg)),
+            _ => options.files.push(arg.clone())
+        }
+    }
+    if options.templates.iter().any(Option::is_some)
+    {
+        options.format = OutputFormat::Custom(node_format(&options.templates).unwrap_or_else(|err| usage_error(program, &err.to_string())));
+    }
+    options
+}
+
+fn node_format(templates : &[Option<String>; 4]) -> Result<NodeFormat, notmecab::FormatError>
+{
+    let mut format = match &templates[0]
+    {
+        Some(node) => NodeFormat::new(node)?,
+        None => NodeFormat::mecab()
+    };
+    if let Some(unk) = &templates[1]
+    {
+        format.set_unk_format(unk)?;
+    }
+    if let Some(bos) = &templates[2]
+    {
+        format.set_bos_format(bos)?;
+    }
+    if let Some(eos) = &templates[3]
+    {
+        format.set_eos_format(eos)?;
+    }
+    Ok(format)
+}
+
+fn open(path : &Path) -> Blob
+{
+    match Blob::open(path)
+    {
+        Ok(blob) => blob,
+        Err(err) =>
+        {
+            eprintln!("failed to open {}: {}", path.display(), err);
+            exit(1);
+        }
+    }
+}
+
+// the compiled file if there is one, the source file otherwise; Dict::load takes either
+fn open_either(directory : &Path, compiled : &str, source : &str) -> Blob
+{
+    let path = directory.join(compiled);
+    if path.exists()
+    {
+        open(&path)
+    }
+    else
+    {
+        open(&directory.join(source))
+    }
+}
+
+fn load_dict(options : &Options, program : &str) -> Dict
+{
+    let directory = match &options.dicdir
+    {
+        Some(directory) => Path::new(directory),
+        None => usage_error(program, "no dictionary directory given (use -d)")
+    };
+    let result = Dict::load(
+        open(&directory.join("sys.dic")),
+        open_either(directory, "unk.dic", "unk.def"),
+        open_either(directory, "matrix.bin", "matrix.def"),
+        open_either(directory, "char.bin", "char.def")
+    );
+    let mut dict = match result
+    {
+        Ok(dict) => dict,
+        Err(err) =>
+        {
+            eprintln!("failed to load {}: {}", directory.display(), err);
+            exit(1);
+        }
+    };
+    for userdic in &options.userdics
+    {
+        if let Err(err) = dict.add_user_dictionary(userdic, open(Path::new(userdic)))
+        {
+            eprintln!("failed to load {}: {}", userdic, err);
+            exit(1);
+        }
+    }
+    dict.set_space_stripping(options.space_stripping);
+    dict.set_unk_forced_processing(options.unk_forced_processing);
+    dict.set_unk_greedy_grouping(options.unk_greedy_grouping);
+    dict.set_unk_prefix_grouping(options.unk_prefix_grouping);
+    dict
+}
+
+// a field by name if the dictionary has a known schema, otherwise by its position in IPADIC, like mecab's dicrc does
+fn field<'a>(features : &'a Features, names : &[&str], ipadic_index : usize) -> Option<&'a str>
+{
+    match features.schema()
+    {
+        Some(schema) => names.iter().filter_map(|name| schema.index_of(name)).next().and_then(|index| features.field(index)),
+        None => features.field(ipadic_index)
+    }
+}
+
+fn reading<'a>(features : &'a Features, surface : &'a str) -> &'a str
+{
+    // UniDic has no reading field; its dicrc uses the pronunciation instead
+    match field(features, &["reading", "pron"], 7)
+    {
+        Some(reading) if reading != "*" && !reading.is_empty() => reading,
+        _ => surface
+    }
+}
+
+fn write_sentence<W : Write>(out : &mut W, dict : &Dict, format : &OutputFormat, text : &str, tokens : &[LexerToken], total_cost : i64) -> io::Result<()>
+{
+    match format
+    {
+        OutputFormat::Custom(node_format) => out.write_all(node_format.render(dict, text, tokens, total_cost).as_bytes()),
+        OutputFormat::Default =>
+        {
+            for token in tokens
+            {
+                writeln!(out, "{}\t{}", token.get_text(text), token.get_feature(dict))?;
+            }
+            writeln!(out, "EOS")
+        }
+        OutputFormat::Wakati =>
+        {
+            for token in tokens
+            {
+                write!(out, "{} ", token.get_text(text))?;
+            }
+            writeln!(out)
+        }
+        OutputFormat::Yomi =>
+        {
+            for token in tokens
+            {
+                let surface = token.get_text(text);
+                if token.kind == TokenType::UNK
+                {
+                    write!(out, "{}", surface)?;
+                }
+                else
+                {
+                    write!(out, "{}", reading(&token.features(dict), surface))?;
+                }
+            }
+            writeln!(out)
+        }
+        OutputFormat::Chasen =>
+        {
+            for token in tokens
+            {
+                let surface = token.get_text(text);
+                let features = token.features(dict);
+                // pos1-pos2-pos3-pos4, leaving out unset levels
+                let pos = features.iter().take(4).filter(|pos| *pos != "*").collect::<Vec<_>>().join("-");
+                if token.kind == TokenType::UNK
+                {
+                    writeln!(out, "{0}\t{0}\t{0}\t{1}\t\t", surface, pos)?;
+                }
+                else
+                {
+                    let lemma = field(&features, &["lemma"], 6).unwrap_or("");
+                    let conjugation_type = field(&features, &["cType"], 4).unwrap_or("");
+                    let conjugation_form = field(&features, &["cForm"], 5).unwrap_or("");
+                    // mecab prints the reading as is here, even when it's *, unlike in yomi
+                    let reading = field(&features, &["reading", "pron"], 7).unwrap_or("");
+                    writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}", surface, reading, lemma, pos, conjugation_type, conjugation_form)?;
+                }
+            }
+            writeln!(out, "EOS")
+        }
+        OutputFormat::Dump =>
+        {
+            // mecab's columns: surface feature begin end rcAttr lcAttr posid char_type stat isbest alpha beta prob cost
+            // stat is 0 for normal nodes, 1 for unknown ones, 2 for BOS and 3 for EOS; alpha, beta and prob are only filled in by mecab -m
+            writeln!(out, " {} 0 0 0 0 0 0 2 1 0 0 0 0", BOS_FEATURE)?;
+            let mut cost = 0;
+            for token in tokens
+            {
+                cost += token.real_cost;
+                let stat = if token.kind == TokenType::UNK { 1 } else { 0 };
+                writeln!(out, "{} {} {} {} {} {} {} 0 {} 1 0 0 0 {}", token.get_text(text), token.get_feature(dict), token.range.start, token.range.end,
+                    token.right_context(), token.left_context(), token.pos(), stat, cost)?;
+            }
+            writeln!(out, " {} {2} {2} 0 0 0 0 3 1 0 0 0 {1}", BOS_FEATURE, total_cost, text.len())?;
+            writeln!(out, "EOS")
+        }
+    }
+}
+
+// io::Error::other needs a newer Rust than the rest of the crate does
+#[allow(clippy::io_other_error)]
+fn tokenize_lines<R : BufRead, W : Write>(reader : R, out : &mut W, dict : &Dict, format : &OutputFormat) -> io::Result<()>
+{
+    let mut cache = notmecab::Cache::new();
+    let mut tokens = Vec::new();
+    for line in reader.lines()
+    {
+        let line = line?;
+        let text = line.strip_suffix('\r').unwrap_or(&line);
+        let total_cost = match dict.tokenize_with_cache(&mut cache, text, &mut tokens)
+        {
+            Ok(cost) => cost,
+            // empty lines, and lines of spaces that space stripping leaves nothing of; mecab prints an empty sentence for them
+            Err(_) if text.bytes().all(|byte| byte == b' ') =>
+            {
+                tokens.clear();
+                0
+            }
+            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, format!("{}: {}", err, text)))
+        };
+        write_sentence(out, dict, format, text, &tokens, total_cost)?;
+    }
+    out.flush()
+}
+
+fn main()
+{
+    let args : Vec<String> = std::env::args().collect();
+    let options = parse_args(&args);
+    let dict = load_dict(&options, &args[0]);
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let mut result = Ok(());
+    if options.files.is_empty()
+    {
+        let stdin = io::stdin();
+        result = tokenize_lines(stdin.lock(), &mut out, &dict, &options.format);
+    }
+    for file in &options.files
+    {
+        result = if file == "-"
+        {
+            let stdin = io::stdin();
+            tokenize_lines(stdin.lock(), &mut out, &dict, &options.format)
+        }
+        else
+        {
+            match File::open(file)
+            {
+                Ok(reader) => tokenize_lines(BufReader::new(reader), &mut out, &dict, &options.format),
+                Err(err) => Err(io::Error::new(err.kind(), format!("failed to open {}: {}", file, err)))
+            }
+        };
+        if result.is_err()
+        {
+            break;
+        }
+    }
+    // whatever was tokenized before an error still gets written out
+    let flushed = out.flush();
+    match result.and(flushed)
+    {
+        Ok(()) => {}
+        // the reader of a pipeline stopped early, e.g. head
+        Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+        Err(err) =>
+        {
+            eprintln!("{}: {}", args[0], err);
+            exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notmecab::CompiledDictionary;
+
+    fn test_dict() -> Dict
+    {
+        let lexicon = "これ,1,1,100,名詞,代名詞,一般,*,*,*,これ,コレ,コレ\nを,1,1,50,助詞,格助詞,一般,*,*,*,を,ヲ,ヲ\nー,1,1,100,記号,一般,*,*,*,*,ー,*,*\n";
+        let unk_def = "DEFAULT,1,1,5000,補助記号,一般,*,*,*,*\nSPACE,1,1,5000,空白,*,*,*,*,*\n";
+        let char_def = "DEFAULT 0 1 0\nSPACE 0 1 0\n0x0020 SPACE\n";
+        CompiledDictionary::compile(&[lexicon], "2 2\n0 1 -10\n1 0 -10\n", char_def, unk_def).unwrap().load().unwrap()
+    }
+
+    fn run(dict : &Dict, format : &OutputFormat, input : &str) -> (String, io::Result<()>)
+    {
+        let mut out = Vec::new();
+        let result = tokenize_lines(input.as_bytes(), &mut out, dict, format);
+        (String::from_utf8(out).unwrap(), result)
+    }
+
+    #[test]
+    fn test_tokenize_lines()
+    {
+        let dict = test_dict();
+        let input = "これを\n\n   \r\nを\n";
+
+        let (output, result) = run(&dict, &OutputFormat::Default, input);
+        result.unwrap();
+        assert_eq!(output, "これ\t名詞,代名詞,一般,*,*,*,これ,コレ,コレ\nを\t助詞,格助詞,一般,*,*,*,を,ヲ,ヲ\nEOS\nEOS\nEOS\nを\t助詞,格助詞,一般,*,*,*,を,ヲ,ヲ\nEOS\n");
+
+        let (output, result) = run(&dict, &OutputFormat::Wakati, input);
+        result.unwrap();
+        assert_eq!(output, "これ を \n\n\nを \n");
+
+        let (output, result) = run(&dict, &OutputFormat::Yomi, input);
+        result.unwrap();
+        assert_eq!(output, "コレヲ\n\n\nヲ\n");
+
+        for format in &[OutputFormat::Dump, OutputFormat::Chasen]
+        {
+            let (output, result) = run(&dict, format, input);
+            result.unwrap();
+            assert_eq!(output.lines().filter(|line| *line == "EOS").count(), 4);
+        }
+
+        let (output, result) = run(&dict, &OutputFormat::Chasen, "これー\n");
+        result.unwrap();
+        assert_eq!(output, "これ\tコレ\tこれ\t名詞-代名詞-一般\t*\t*\nー\t*\tー\t記号-一般\t*\t*\nEOS\n");
+
+        // lines that can't be read still fail, after the lines before them are written
+        let mut out = Vec::new();
+        let input = ["これ\n".as_bytes(), b"\xFF\n"].concat();
+        let result = tokenize_lines(&input[..], &mut out, &dict, &OutputFormat::Wakati);
+        assert_eq!(out, "これ \n".as_bytes());
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
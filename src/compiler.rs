@@ -0,0 +1,385 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::HashMap;
+
+use super::dart::*;
+use super::unkchar::*;
+use super::features::split_csv_field;
+use super::error::*;
+use super::file::decode_text;
+use super::Blob;
+use super::Dict;
+use super::FormatToken;
+
+/// The four files of a compiled mecab dictionary, built from the dictionary's source files.
+///
+/// The output is in the same format as mecab-dict-index's (version 0x66, UTF-8), so it can be loaded with
+/// [`Dict::load`] or written out next to the sources and used by other mecab-compatible tools.
+pub struct CompiledDictionary {
+    /// Contents of sys.dic.
+    pub sys_dic : Vec<u8>,
+    /// Contents of unk.dic.
+    pub unk_dic : Vec<u8>,
+    /// Contents of matrix.bin.
+    pub matrix : Vec<u8>,
+    /// Contents of char.bin.
+    pub char_bin : Vec<u8>,
+}
+
+struct Entry<'a> {
+    surface : String,
+    token : FormatToken,
+    feature : &'a str,
+    // where the entry was defined
+    file : DictFile,
+    line : usize,
+}
+
+// Parses the surface, left context ID, right context ID and cost at the start of a line.
+// Everything past the fourth comma is the feature string, kept verbatim.
+fn parse_entry(text : &str, file : DictFile, line : usize) -> Result<Entry<'_>, LoadError>
+{
+    let error = |message| LoadError::Syntax { file, line, message };
+    let (surface, rest) = split_csv_field(text);
+    let surface = surface.into_owned();
+    let rest = rest.ok_or(error("dictionary entry has too few fields"))?;
+    let mut fields = rest.splitn(4, ',');
+    let mut number = || fields.next().map(|field| field.trim()).ok_or(error("dictionary entry has too few fields"));
+    let left_context = number()?.parse::<u16>().map_err(|_| error("dictionary entry has an invalid left context ID"))?;
+    let right_context = number()?.parse::<u16>().map_err(|_| error("dictionary entry has an invalid right context ID"))?;
+    let cost = number()?.parse::<i16>().map_err(|_| error("dictionary entry has a cost that isn't a 16-bit number"))?;
+    let feature = fields.next().ok_or(error("dictionary entry has too few fields"))?;
+    if surface.is_empty()
+    {
+        return Err(error("dictionary entry has an empty surface"));
+    }
+    Ok(Entry {
+        surface,
+        token : FormatToken {
+            left_context,
+            right_context,
+            pos : 0,
+            cost : cost as i64,
+            original_id : 0,
+            feature_offset : 0,
+        },
+        feature,
+        file,
+        line,
+    })
+}
+
+fn parse_entries(text : &str, file : DictFile) -> Result<Vec<Entry<'_>>, LoadError>
+{
+    text.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| parse_entry(line, file, index + 1))
+        .collect()
+}
+
+fn compile_entries(mut entries : Vec<Entry>, dict_type : u32, left_contexts : u32, right_contexts : u32) -> Result<Vec<u8>, LoadError>
+{
+    for entry in &entries
+    {
+        if entry.token.right_context as u32 >= left_contexts || entry.token.left_context as u32 >= right_contexts
+        {
+            return Err(LoadError::Syntax { file : entry.file, line : entry.line, message : "dictionary entry has a context ID that is out of range of the connection matrix" });
+        }
+    }
+
+    // stable, so entries with the same surface keep the order they were defined in
+    entries.sort_by(|a, b| a.surface.as_bytes().cmp(b.surface.as_bytes()));
+
+    let mut features : Vec<u8> = Vec::new();
+    let mut feature_offsets : HashMap<&str, u32> = HashMap::new();
+    let mut tokens = Vec::with_capacity(entries.len());
+    for entry in &entries
+    {
+        let offset = *feature_offsets.entry(entry.feature).or_insert_with(|| {
+            let offset = features.len() as u32;
+            features.extend_from_slice(entry.feature.as_bytes());
+            features.push(0);
+            offset
+        });
+        let mut token = entry.token.clone();
+        token.original_id = tokens.len() as u32;
+        token.feature_offset = offset;
+        tokens.push(token);
+    }
+
+    let mut surfaces : Vec<(&[u8], std::ops::Range<usize>)> = Vec::new();
+    for (index, entry) in entries.iter().enumerate()
+    {
+        match surfaces.last_mut()
+        {
+            Some((surface, range)) if *surface == entry.surface.as_bytes() => range.end = index + 1,
+            _ => surfaces.push((entry.surface.as_bytes(), index..index + 1))
+        }
+    }
+
+    let file = match dict_type { 0 => DictFile::SysDic, 1 => DictFile::UserDic, _ => DictFile::UnkDic };
+    write_mecab_dart_file(dict_type, left_contexts, right_contexts, &surfaces, &tokens, &features)
+        .map_err(|message| LoadError::Invalid { file, message })
+}
+
+/// Compiles the text of an unk.def file into the format of unk.dic. `categories` are the character category names from char.def.
+pub (crate) fn compile_unk_def(text : &str, categories : &[String], left_contexts : u32, right_contexts : u32) -> Result<Vec<u8>, LoadError>
+{
+    let entries = parse_entries(text, DictFile::UnkDef)?;
+    for entry in &entries
+    {
+        if !categories.contains(&entry.surface)
+        {
+            return Err(LoadError::Syntax { file : DictFile::UnkDef, line : entry.line, message : "category isn't defined in char.def" });
+        }
+    }
+    compile_entries(entries, 2, left_contexts, right_contexts)
+}
+
+/// Parses the text of a matrix.def file. Returns the left and right sizes and the costs in the order matrix.bin stores them.
+pub (crate) fn parse_matrix_def(text : &str) -> Result<(u16, u16, Vec<i16>), LoadError>
+{
+    let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    let (header_index, header) = lines.next().ok_or(LoadError::Invalid { file : DictFile::MatrixDef, message : "matrix.def is empty" })?;
+    let mut sizes = header.split_whitespace().map(|field| field.parse::<u16>());
+    let (left_size, right_size) = match (sizes.next(), sizes.next(), sizes.next())
+    {
+        (Some(Ok(left_size)), Some(Ok(right_size)), None) => (left_size, right_size),
+        _ => return Err(LoadError::Syntax { file : DictFile::MatrixDef, line : header_index + 1, message : "header must be the left and right sizes" })
+    };
+
+    let mut costs = vec!(0i16; left_size as usize * right_size as usize);
+    for (index, line) in lines
+    {
+        let error = |message| LoadError::Syntax { file : DictFile::MatrixDef, line : index + 1, message };
+        let mut fields = line.split_whitespace();
+        let mut number = || fields.next().ok_or(error("lines must be a right context ID, a left context ID and a cost"));
+        let left = number()?.parse::<u16>().map_err(|_| error("invalid context ID"))?;
+        let right = number()?.parse::<u16>().map_err(|_| error("invalid context ID"))?;
+        let cost = number()?.parse::<i16>().map_err(|_| error("cost isn't a 16-bit number"))?;
+        if left >= left_size || right >= right_size
+        {
+            return Err(error("context ID is out of range"));
+        }
+        costs[left_size as usize * right as usize + left as usize] = cost;
+    }
+    Ok((left_size, right_size, costs))
+}
+
+/// Compiles the text of a matrix.def file into the format of matrix.bin.
+pub (crate) fn compile_matrix_def(text : &str) -> Result<Vec<u8>, LoadError>
+{
+    let (left_size, right_size, costs) = parse_matrix_def(text)?;
+    let mut matrix = Vec::with_capacity(4 + costs.len() * 2);
+    matrix.extend_from_slice(&left_size.to_le_bytes());
+    matrix.extend_from_slice(&right_size.to_le_bytes());
+    for cost in costs
+    {
+        matrix.extend_from_slice(&cost.to_le_bytes());
+    }
+    Ok(matrix)
+}
+
+fn read_source(path : &Path, file : DictFile) -> Result<String, LoadError>
+{
+    let bytes = fs::read(path).map_err(|source| LoadError::Source { path : path.to_owned(), source })?;
+    decode_text(&bytes, file)?;
+    Ok(String::from_utf8(bytes).unwrap())
+}
+
+impl CompiledDictionary {
+    /// Compiles a dictionary from the contents of its source files.
+    ///
+    /// `lexicons` are the contents of the lexicon CSV files, the rest are the contents of matrix.def, char.def and unk.def.
+    ///
+    /// Lexicon and unk.def lines are the surface (or, for unk.def, the character category), left context ID, right context ID and cost, followed by the feature string. Context IDs must be given explicitly; rewriting them with left-id.def and right-id.def is not supported.
+    pub fn compile(lexicons : &[&str], matrix_def : &str, char_def : &str, unk_def : &str) -> Result<CompiledDictionary, LoadError>
+    {
+        let matrix = compile_matrix_def(matrix_def)?;
+        let left_size = u16::from_le_bytes([matrix[0], matrix[1]]);
+        let right_size = u16::from_le_bytes([matrix[2], matrix[3]]);
+
+        let char_definition = parse_char_def(char_def)?;
+
+        let mut entries = Vec::new();
+        for (index, lexicon) in lexicons.iter().enumerate()
+        {
+            entries.extend(parse_entries(lexicon, DictFile::Lexicon(index))?);
+        }
+        let sys_dic = compile_entries(entries, 0, left_size as u32, right_size as u32)?;
+
+        let unk_dic = compile_unk_def(unk_def, &char_definition.names, left_size as u32, right_size as u32)?;
+
+        Ok(CompiledDictionary {
+            sys_dic,
+            unk_dic,
+            matrix,
+            char_bin : char_definition.to_char_bin(),
+        })
+    }
+
+    /// Compiles the dictionary sources in a directory, like mecab-dict-index does.
+    ///
+    /// Reads every *.csv file in the directory as a lexicon, plus matrix.def, char.def and unk.def. If there is a dicrc that sets config-charset, it must be UTF-8.
+    pub fn compile_directory(source : impl AsRef<Path>) -> Result<CompiledDictionary, LoadError>
+    {
+        let source = source.as_ref();
+
+        if let Ok(dicrc) = fs::read_to_string(source.join("dicrc"))
+        {
+            for (index, line) in dicrc.lines().enumerate()
+            {
+                let mut parts = line.splitn(2, '=');
+                let key = parts.next().unwrap().trim();
+                let value = parts.next().unwrap_or("").trim().to_lowercase();
+                if key == "config-charset" && value != "utf-8" && value != "utf8"
+                {
+                    return Err(LoadError::Syntax { file : DictFile::Dicrc, line : index + 1, message : "only UTF-8 dictionaries are supported. stop using legacy encodings for infrastructure!" });
+                }
+            }
+        }
+
+        let directory_error = |error| LoadError::Source { path : source.to_owned(), source : error };
+        let mut lexicon_paths = Vec::new();
+        for entry in fs::read_dir(source).map_err(directory_error)?
+        {
+            let path = entry.map_err(directory_error)?.path();
+            if path.extension().map(|extension| extension == "csv").unwrap_or(false)
+            {
+                lexicon_paths.push(path);
+            }
+        }
+        lexicon_paths.sort();
+        let mut lexicons = Vec::new();
+        for (index, path) in lexicon_paths.iter().enumerate()
+        {
+            lexicons.push(read_source(path, DictFile::Lexicon(index))?);
+        }
+        let lexicons : Vec<&str> = lexicons.iter().map(|lexicon| lexicon.as_str()).collect();
+
+        let matrix_def = read_source(&source.join("matrix.def"), DictFile::MatrixDef)?;
+        let char_def = read_source(&source.join("char.def"), DictFile::CharDef)?;
+        let unk_def = read_source(&source.join("unk.def"), DictFile::UnkDef)?;
+
+        Self::compile(&lexicons, &matrix_def, &char_def, &unk_def)
+    }
+
+    /// Writes sys.dic, unk.dic, matrix.bin and char.bin into a directory.
+    pub fn write_to_directory(&self, target : impl AsRef<Path>) -> io::Result<()>
+    {
+        let target = target.as_ref();
+        fs::write(target.join("sys.dic"), &self.sys_dic)?;
+        fs::write(target.join("unk.dic"), &self.unk_dic)?;
+        fs::write(target.join("matrix.bin"), &self.matrix)?;
+        fs::write(target.join("char.bin"), &self.char_bin)?;
+        Ok(())
+    }
+
+    /// Loads the compiled dictionary directly, without writing it out first.
+    pub fn load(self) -> Result<Dict, LoadError>
+    {
+        Dict::load(Blob::new(self.sys_dic), Blob::new(self.unk_dic), Blob::new(self.matrix), Blob::new(self.char_bin))
+    }
+}
+
+#[cfg(test)]
+pub (crate) mod tests {
+    use super::*;
+    use crate::FeatureSchema;
+
+    pub (crate) const LEXICON : &str = "\
+これ,1,1,100,代名詞,*,*,*,*,*,コレ,此れ,これ
+を,2,2,50,助詞,格助詞,*,*,*,*,ヲ,を,を
+持っ,3,3,200,動詞,一般,*,*,五段-タ行,連用形-促音便,モツ,持つ,持っ
+て,2,2,50,助詞,接続助詞,*,*,*,*,テ,て,て
+いけ,3,3,300,動詞,非自立可能,*,*,五段-カ行,命令形,イク,行く,いけ
+い,3,3,400,動詞,非自立可能,*,*,上一段-ア行,連用形-一般,イル,居る,い
+け,1,1,900,名詞,普通名詞,一般,*,*,*,ケ,毛,け
+\"飛行\",1,1,300,名詞,普通名詞,サ変可能,*,*,*,ヒコウ,飛行,飛行
+機,1,1,300,名詞,普通名詞,一般,*,*,*,キ,機,機
+機,1,1,1300,名詞,普通名詞,一般,*,*,*,ハタ,機,機
+";
+    pub (crate) const MATRIX_DEF : &str = "\
+4 4
+0 0 0
+0 1 -10
+1 2 -20
+2 3 -20
+3 2 -20
+3 0 -10
+1 1 50
+";
+    pub (crate) const CHAR_DEF : &str = "\
+DEFAULT 0 1 0
+SPACE 0 1 0
+KANJI 0 0 2
+ALPHA 1 1 0
+0x0020 SPACE
+0x4E00..0x9FFF KANJI
+0x0041..0x005A ALPHA
+0x0061..0x007A ALPHA
+";
+    pub (crate) const UNK_DEF : &str = "\
+DEFAULT,1,1,5000,補助記号,一般,*,*,*,*
+SPACE,1,1,5000,空白,*,*,*,*,*
+KANJI,1,1,3000,名詞,普通名詞,一般,*,*,*
+ALPHA,1,1,2000,名詞,固有名詞,一般,*,*,*
+";
+
+    /// The sources above, compiled, for tests that need the dictionary files themselves.
+    pub (crate) fn compiled_test_dict() -> CompiledDictionary
+    {
+        CompiledDictionary::compile(&[LEXICON], MATRIX_DEF, CHAR_DEF, UNK_DEF).unwrap()
+    }
+
+    /// Compiles user dictionary entries into a .dic file, for tests of loading compiled user dictionaries.
+    pub (crate) fn compile_user_dict(text : &str, dict_type : u32, left_contexts : u32, right_contexts : u32) -> Vec<u8>
+    {
+        compile_entries(parse_entries(text, DictFile::UserDic).unwrap(), dict_type, left_contexts, right_contexts).unwrap()
+    }
+
+    /// A small dictionary for tests that don't need a real one.
+    pub (crate) fn test_dict() -> Dict
+    {
+        compiled_test_dict().load().unwrap()
+    }
+
+    #[test]
+    fn test_compile()
+    {
+        let mut dict = compiled_test_dict().load().unwrap();
+
+        let text = "これを持っていけ";
+        let (tokens, _) = dict.tokenize(text).unwrap();
+        let surfaces : Vec<&str> = tokens.iter().map(|token| token.get_text(text)).collect();
+        assert_eq!(surfaces, vec!("これ", "を", "持っ", "て", "いけ"));
+        assert_eq!(tokens[2].get_feature(&dict), "動詞,一般,*,*,五段-タ行,連用形-促音便,モツ,持つ,持っ");
+
+        // nine fields, same as ipadic
+        assert_eq!(dict.feature_schema(), Some(&FeatureSchema::ipadic()));
+        dict.set_feature_schema(Some(FeatureSchema::unidic()));
+        assert_eq!(tokens[2].features(&dict).get("lemma"), Some("持つ"));
+
+        let text = "飛行機 abc";
+        let (tokens, _) = dict.tokenize(text).unwrap();
+        let surfaces : Vec<&str> = tokens.iter().map(|token| token.get_text(text)).collect();
+        assert_eq!(surfaces, vec!("飛行", "機", "abc"));
+        assert_eq!(tokens[1].get_feature(&dict), "名詞,普通名詞,一般,*,*,*,キ,機,機");
+        assert_eq!(tokens[2].get_feature(&dict), "名詞,固有名詞,一般,*,*,*");
+
+        assert!(matches!(
+            CompiledDictionary::compile(&[LEXICON, "\nこれ,1,1,100000,代名詞"], MATRIX_DEF, CHAR_DEF, UNK_DEF),
+            Err(LoadError::Syntax { file : DictFile::Lexicon(1), line : 2, .. })
+        ));
+        assert!(matches!(
+            CompiledDictionary::compile(&["これ,9,1,100,代名詞"], MATRIX_DEF, CHAR_DEF, UNK_DEF),
+            Err(LoadError::Syntax { file : DictFile::Lexicon(0), line : 1, .. })
+        ));
+        assert!(matches!(
+            CompiledDictionary::compile(&[LEXICON], MATRIX_DEF, CHAR_DEF, "HIRAGANA,1,1,100,名詞"),
+            Err(LoadError::Syntax { file : DictFile::UnkDef, line : 1, .. })
+        ));
+    }
+}
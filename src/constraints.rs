@@ -0,0 +1,162 @@
+use std::ops::Range;
+
+/// Restrictions on how [`crate::Dict::tokenize_constrained`] is allowed to split up a string, similar to mecab's partial parsing mode.
+///
+/// All positions are byte offsets into the text that's being tokenized, and should be on character boundaries.
+/// Required boundaries and tokens past the end of the text can never be satisfied, so tokenizing with any of them fails. Forbidden boundaries past the end are trivially satisfied and ignored.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Default)]
+pub struct Constraints {
+    boundaries : Vec<usize>,
+    non_boundaries : Vec<usize>,
+    // sorted by start; expected not to overlap
+    spans : Vec<(Range<usize>, Option<String>)>,
+}
+
+fn insert_sorted(list : &mut Vec<usize>, position : usize)
+{
+    if let Err(index) = list.binary_search(&position)
+    {
+        list.insert(index, position);
+    }
+}
+
+impl Constraints {
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// Requires that no token crosses the given position.
+    pub fn require_boundary(&mut self, position : usize)
+    {
+        insert_sorted(&mut self.boundaries, position);
+    }
+
+    /// Requires that no token starts or ends at the given position.
+    ///
+    /// Note that a position inside of a run of 0x20 spaces that get stripped away can never be inside of a token, so forbidding a boundary there makes the tokenization fail.
+    pub fn forbid_boundary(&mut self, position : usize)
+    {
+        insert_sorted(&mut self.non_boundaries, position);
+    }
+
+    /// Requires that the given range comes out as a single token. Ranges must not overlap each other.
+    ///
+    /// If `feature_prefix` is given, the token's feature string must also start with it.
+    ///
+    /// If the dictionaries have no fitting token for the range, it's covered by an unknown token of the category of its first character instead.
+    pub fn require_token(&mut self, range : Range<usize>, feature_prefix : Option<&str>)
+    {
+        let index = self.spans.partition_point(|(span, _)| span.start < range.start);
+        self.spans.insert(index, (range, feature_prefix.map(|prefix| prefix.to_string())));
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.boundaries.is_empty() && self.non_boundaries.is_empty() && self.spans.is_empty()
+    }
+
+    /// Whether every required boundary and token is inside of a text of the given length.
+    pub(crate) fn fits_within(&self, length : usize) -> bool
+    {
+        self.boundaries.last().map(|&position| position <= length).unwrap_or(true)
+            && self.spans.iter().all(|(span, _)| span.end <= length)
+    }
+
+    fn overlapping_spans(&self, range : &Range<usize>) -> impl Iterator<Item = &(Range<usize>, Option<String>)>
+    {
+        let end = self.spans.partition_point(|(span, _)| span.start <= range.end);
+        let start = range.start;
+        self.spans[..end].iter().rev().take_while(move |(span, _)| span.end >= start)
+    }
+
+    /// Whether a token may start or end at the given position.
+    pub(crate) fn allows_boundary(&self, position : usize) -> bool
+    {
+        self.non_boundaries.binary_search(&position).is_err()
+            && self.overlapping_spans(&(position..position)).all(|(span, _)| position <= span.start || position >= span.end)
+    }
+
+    /// Whether a token may cover the given range. Doesn't check feature prefixes.
+    pub(crate) fn allows_range(&self, range : &Range<usize>) -> bool
+    {
+        let first_inside = self.boundaries.partition_point(|&position| position <= range.start);
+        if self.boundaries.get(first_inside).map(|&position| position < range.end).unwrap_or(false)
+        {
+            return false;
+        }
+        if !self.allows_boundary(range.start) || !self.allows_boundary(range.end)
+        {
+            return false;
+        }
+        let crosses = |position : usize| range.start < position && position < range.end;
+        self.overlapping_spans(range).all(|(span, _)| !crosses(span.start) && !crosses(span.end))
+    }
+
+    /// Whether a token covering the given range with the given feature string satisfies the feature prefix of a required token.
+    pub(crate) fn allows_feature(&self, range : &Range<usize>, feature : &str) -> bool
+    {
+        self.overlapping_spans(range).all(|(span, prefix)|
+            span != range || prefix.as_ref().map(|prefix| feature.starts_with(prefix.as_str())).unwrap_or(true)
+        )
+    }
+
+    /// Required tokens starting at the given position.
+    pub(crate) fn required_tokens_at(&self, position : usize) -> impl Iterator<Item = &(Range<usize>, Option<String>)>
+    {
+        let start = self.spans.partition_point(|(span, _)| span.start < position);
+        self.spans[start..].iter().take_while(move |(span, _)| span.start == position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constraints()
+    {
+        let mut constraints = Constraints::new();
+        assert!(constraints.is_empty());
+        assert!(constraints.allows_range(&(0..10)));
+
+        constraints.require_boundary(3);
+        assert!(!constraints.allows_range(&(0..10)));
+        assert!(constraints.allows_range(&(0..3)));
+        assert!(constraints.allows_range(&(3..10)));
+
+        constraints.forbid_boundary(6);
+        assert!(!constraints.allows_range(&(3..6)));
+        assert!(!constraints.allows_range(&(6..9)));
+        assert!(constraints.allows_range(&(3..9)));
+        assert!(!constraints.allows_boundary(6));
+
+        constraints.require_token(12..18, Some("名詞"));
+        assert!(constraints.allows_range(&(12..18)));
+        assert!(!constraints.allows_range(&(9..15)));
+        assert!(!constraints.allows_range(&(12..15)));
+        assert!(!constraints.allows_range(&(15..21)));
+        assert!(!constraints.allows_range(&(9..21)));
+        assert!(constraints.allows_range(&(9..12)));
+        assert!(constraints.allows_range(&(18..21)));
+        assert!(!constraints.allows_boundary(15));
+        assert!(constraints.allows_boundary(12));
+
+        assert!(constraints.allows_feature(&(12..18), "名詞,普通名詞"));
+        assert!(!constraints.allows_feature(&(12..18), "動詞,一般"));
+        assert!(constraints.allows_feature(&(9..12), "動詞,一般"));
+
+        assert_eq!(constraints.required_tokens_at(12).count(), 1);
+        assert_eq!(constraints.required_tokens_at(9).count(), 0);
+
+        assert!(constraints.fits_within(18));
+        assert!(!constraints.fits_within(17));
+        constraints.forbid_boundary(30);
+        assert!(constraints.fits_within(18));
+        constraints.require_boundary(30);
+        assert!(!constraints.fits_within(29));
+        assert!(constraints.fits_within(30));
+    }
+}
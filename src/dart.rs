@@ -1,52 +1,210 @@
-use crate::HashMap;
-use crate::HashSet;
-
-use std::io::Cursor;
-use std::io::Read;
-use std::io::Seek;
+use std::borrow::Cow;
 use std::ops::Range;
-use std::hash::{BuildHasherDefault, Hasher};
 
 use super::blob::*;
 use super::file::*;
+use super::error::*;
 use super::FormatToken;
 
-type BuildNoopHasher = BuildHasherDefault<NoopHasher>;
+#[derive(Debug)]
+pub (crate) struct Link {
+    base : u32,
+    check : u32
+}
 
-#[derive(Default)]
-struct NoopHasher(u64);
+// Builds a double-array trie in the layout collect_links reads: a node with base b has its children at b+1+byte,
+// each with check == b, and its output (if any) at b itself, with check == b and base == !value.
+struct LinkBuilder {
+    links : Vec<Link>,
+    used : Vec<bool>,
+    used_bases : Vec<bool>,
+    next_check_pos : usize,
+}
 
-impl Hasher for NoopHasher {
-    fn finish(&self) -> u64
+impl LinkBuilder {
+    fn is_used(&self, index : usize) -> bool
     {
-        self.0
+        self.used.get(index).copied().unwrap_or(false)
     }
-
-    fn write(&mut self, bytes : &[u8])
+    fn is_used_base(&self, base : usize) -> bool
+    {
+        self.used_bases.get(base).copied().unwrap_or(false)
+    }
+    fn reserve(&mut self, index : usize)
     {
-        for &byte in bytes
+        if index >= self.links.len()
         {
-            self.0 = (self.0 << 8) ^ (byte as u64);
+            self.links.resize_with(index + 1, || Link { base : 0, check : 0 });
+            self.used.resize(index + 1, false);
         }
+        self.used[index] = true;
     }
-
-    fn write_u64(&mut self, value : u64)
+    fn find_base(&mut self, codes : &[(usize, Range<usize>)]) -> usize
     {
-        self.0 ^= value;
+        let first_code = codes[0].0;
+        let mut position = std::cmp::max(self.next_check_pos, first_code + 1);
+        let mut used_count = 0;
+        loop
+        {
+            if self.is_used(position)
+            {
+                used_count += 1;
+            }
+            else
+            {
+                let base = position - first_code;
+                if !self.is_used_base(base) && codes.iter().all(|(code, _)| !self.is_used(base + code))
+                {
+                    // skip over densely packed areas next time
+                    if used_count * 20 >= (position - self.next_check_pos + 1) * 19
+                    {
+                        self.next_check_pos = position;
+                    }
+                    return base;
+                }
+            }
+            position += 1;
+        }
+    }
+    fn place(&mut self, entries : &[(&[u8], u32)], depth : usize) -> Result<u32, &'static str>
+    {
+        // code 0 is for a key that ends here, byte+1 for keys that continue
+        let code_of = |key : &[u8]| if key.len() == depth { 0 } else { key[depth] as usize + 1 };
+        let mut codes : Vec<(usize, Range<usize>)> = Vec::new();
+        let mut start = 0;
+        while start < entries.len()
+        {
+            let code = code_of(entries[start].0);
+            let end = start + entries[start..].iter().take_while(|entry| code_of(entry.0) == code).count();
+            if codes.last().map(|last| last.0 >= code).unwrap_or(false)
+            {
+                return Err("dictionary keys are not sorted");
+            }
+            codes.push((code, start..end));
+            start = end;
+        }
+        
+        let base = self.find_base(&codes);
+        if base >= 0x8000_0000
+        {
+            return Err("too many dictionary keys to fit into a double-array trie");
+        }
+        if base >= self.used_bases.len()
+        {
+            self.used_bases.resize(base + 1, false);
+        }
+        self.used_bases[base] = true;
+        for (code, _) in &codes
+        {
+            self.reserve(base + code);
+            self.links[base + code].check = base as u32;
+        }
+        
+        for (code, range) in codes
+        {
+            if code == 0
+            {
+                if range.len() != 1
+                {
+                    return Err("duplicate dictionary key");
+                }
+                let value = entries[range.start].1;
+                if value >= 0x8000_0000
+                {
+                    return Err("dictionary value too large to store in a double-array trie");
+                }
+                self.links[base].base = !value;
+            }
+            else
+            {
+                let child = self.place(&entries[range], depth + 1)?;
+                self.links[base + code].base = child;
+            }
+        }
+        Ok(base as u32)
     }
 }
 
-#[derive(Debug)]
-pub (crate) struct Link {
-    base : u32,
-    check : u32
+/// Builds the links of a double-array trie mapping each key to its value. `entries` must be sorted by key, without duplicates.
+pub (crate) fn build_links(entries : &[(&[u8], u32)]) -> Result<Vec<Link>, &'static str>
+{
+    let mut builder = LinkBuilder {
+        links : vec!(Link { base : 0, check : 0 }),
+        used : vec!(true),
+        used_bases : vec!(true),
+        next_check_pos : 1,
+    };
+    let root = if entries.is_empty() { 1 } else { builder.place(entries, 0)? };
+    builder.links[0].base = root;
+    // keep lookups past the last used link in bounds
+    let padded_length = builder.links.len() + 0x100;
+    builder.links.resize_with(padded_length, || Link { base : 0, check : 0 });
+    Ok(builder.links)
 }
 
-impl Link {
-    pub (crate) fn read<T : Read>(sysdic : &mut T) -> Result<Link, &'static str>
+const DICTIONARY_MAGIC_ID : u32 = 0xEF71_8F77;
+
+/// Serializes a dictionary in the format load_mecab_dart_file reads.
+///
+/// `entries` are the surfaces with the range of `tokens` that belongs to each of them, sorted by surface, and `features` is the feature string pile the tokens' feature offsets point into.
+pub (crate) fn write_mecab_dart_file(dict_type : u32, left_contexts : u32, right_contexts : u32, entries : &[(&[u8], Range<usize>)], tokens : &[FormatToken], features : &[u8]) -> Result<Vec<u8>, &'static str>
+{
+    let mut values = Vec::with_capacity(entries.len());
+    for (surface, range) in entries
+    {
+        if range.is_empty() || range.len() > 0xFF
+        {
+            return Err("every dictionary surface must have between 1 and 255 tokens");
+        }
+        values.push((*surface, ((range.start as u32) << 8) | range.len() as u32));
+    }
+    let links = build_links(&values)?;
+    
+    let mut token_bytes = Vec::with_capacity(tokens.len() * 16);
+    for token in tokens
     {
-        Ok(Link{base : read_u32(sysdic)?, check : read_u32(sysdic)?})
+        if token.cost < i16::MIN as i64 || token.cost > i16::MAX as i64
+        {
+            return Err("token cost does not fit into 16 bits");
+        }
+        token_bytes.extend_from_slice(&token.left_context.to_le_bytes());
+        token_bytes.extend_from_slice(&token.right_context.to_le_bytes());
+        token_bytes.extend_from_slice(&token.pos.to_le_bytes());
+        token_bytes.extend_from_slice(&(token.cost as i16).to_le_bytes());
+        token_bytes.extend_from_slice(&token.feature_offset.to_le_bytes());
+        token_bytes.extend_from_slice(&0u32.to_le_bytes());
     }
+    
+    let link_bytes = links.len() * 8;
+    let file_size = 0x48 + link_bytes + token_bytes.len() + features.len();
+    let mut out = Vec::with_capacity(file_size);
+    let header = [
+        file_size as u32 ^ DICTIONARY_MAGIC_ID,
+        0x66,
+        dict_type,
+        tokens.len() as u32,
+        left_contexts,
+        right_contexts,
+        link_bytes as u32,
+        token_bytes.len() as u32,
+        features.len() as u32,
+        0
+    ];
+    for value in header.iter()
+    {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    let mut encoding = [0u8; 0x20];
+    encoding[..5].copy_from_slice(b"UTF-8");
+    out.extend_from_slice(&encoding);
+    for link in &links
+    {
+        out.extend_from_slice(&link.base.to_le_bytes());
+        out.extend_from_slice(&link.check.to_le_bytes());
+    }
+    out.extend_from_slice(&token_bytes);
+    out.extend_from_slice(features);
+    Ok(out)
 }
 
 fn check_valid_link(links : &[Link], from : u32, to : u32) -> Result<(), i32>
@@ -103,56 +261,163 @@ fn collect_links(links : &[Link], base : u32, collection : &mut Vec<(String, u32
     }
 }
 
-#[derive(Debug)]
-#[derive(Clone)]
-#[derive(Copy)]
-pub(crate) struct DictInfo {
-    first : u32,
-    end   : u32,
+enum TokenTable {
+    Loaded(Vec<FormatToken>),
+    // the range of the blob holding the 16-byte token records, decoded on lookup
+    Lazy(Range<usize>),
 }
 
-fn entries_to_tokens(entries : Vec<(String, u32)>) -> HashMap<String, DictInfo>
-{
-    entries.into_iter().map(|entry| {
-        let first : u32 = entry.1 / 0x100;
-        let end   : u32 = (entry.1 % 0x100) + first;
-        (entry.0, DictInfo{first, end})
-    }).collect()
+/// The tokens of one trie entry, borrowed from the loaded token table or decoded from the blob one at a time.
+#[derive(Clone)]
+pub (crate) enum Tokens<'a> {
+    Loaded(std::slice::Iter<'a, FormatToken>),
+    Lazy { dict : &'a DartDict, indices : Range<usize> },
 }
 
-fn collect_links_into_map(links : Vec<Link>) -> HashMap<String, DictInfo>
-{
-    let mut collection : Vec<(String, u32)> = Vec::new();
-    collect_links(&links, links[0].base, &mut collection, &[]);
-    entries_to_tokens(collection)
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Cow<'a, FormatToken>;
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        match self
+        {
+            Tokens::Loaded(iter) => iter.next().map(Cow::Borrowed),
+            Tokens::Lazy { dict, indices } => indices.next().and_then(|index| dict.token(index)),
+        }
+    }
+    fn size_hint(&self) -> (usize, Option<usize>)
+    {
+        match self
+        {
+            Tokens::Loaded(iter) => iter.size_hint(),
+            Tokens::Lazy { indices, .. } => indices.size_hint(),
+        }
+    }
 }
 
+impl<'a> ExactSizeIterator for Tokens<'a> {}
+
 pub (crate) struct DartDict {
-    pub(crate) dict : HashMap<String, DictInfo>,
-    pub(crate) tokens : Vec<FormatToken>,
-    contains_longer : HashSet<u64, BuildNoopHasher>,
+    pub(crate) dict_type : u32,
+    tokens : TokenTable,
     pub(crate) left_contexts : u32,
     pub(crate) right_contexts : u32,
+    // the double-array trie, read straight out of the blob on lookup
+    links_range : Range<usize>,
     feature_bytes_range : Range<usize>,
     blob : Blob
 }
 
 impl DartDict {
-    pub (crate) fn may_contain(&self, hash : u64) -> bool
+    fn link(&self, index : u32) -> Option<Link>
+    {
+        let start = self.links_range.start.checked_add((index as usize).checked_mul(8)?)?;
+        if start + 8 > self.links_range.end
+        {
+            return None;
+        }
+        let mut bytes = &self.blob[start..start + 8];
+        Some(Link { base : read_u32(&mut bytes).ok()?, check : read_u32(&mut bytes).ok()? })
+    }
+    fn root(&self) -> u32
+    {
+        // the link table was checked to be non-empty at load
+        self.link(0).map(|link| link.base).unwrap_or(0)
+    }
+    // follows the edge for the given byte out of the node with the given base, returning the base of the node it leads to
+    fn child(&self, base : u32, byte : u8) -> Option<u32>
+    {
+        let link = self.link(base.checked_add(1 + byte as u32)?)?;
+        // make sure we don't follow a link back where we started
+        if link.check != base || link.base == base
+        {
+            return None;
+        }
+        Some(link.base)
+    }
+    // the value stored at the node with the given base, if any
+    fn output(&self, base : u32) -> Option<u32>
+    {
+        let link = self.link(base)?;
+        if link.check != base || link.base == base || link.base < 0x8000_0000
+        {
+            return None;
+        }
+        Some(!link.base)
+    }
+    fn value_range(value : u32) -> Range<usize>
     {
-        self.contains_longer.contains(&hash)
+        let first = (value / 0x100) as usize;
+        first..first + (value % 0x100) as usize
     }
-    pub (crate) fn dic_get<'a>(&'a self, find : &str) -> Option<&'a [FormatToken]>
+    pub (crate) fn token_count(&self) -> usize
     {
-        if let Some(info) = self.dict.get(find)
+        match &self.tokens
         {
-            Some(&self.tokens[info.first as usize..info.end as usize])
+            TokenTable::Loaded(tokens) => tokens.len(),
+            TokenTable::Lazy(range) => range.len() / 16,
         }
-        else
+    }
+    pub (crate) fn token(&self, index : usize) -> Option<Cow<'_, FormatToken>>
+    {
+        match &self.tokens
         {
-            None
+            TokenTable::Loaded(tokens) => tokens.get(index).map(Cow::Borrowed),
+            TokenTable::Lazy(range) =>
+            {
+                if index >= self.token_count()
+                {
+                    return None;
+                }
+                let start = range.start + index * 16;
+                let mut reader = FileReader::new(&self.blob[start..start + 16], DictFile::SysDic);
+                FormatToken::read(&mut reader, index as u32).ok().map(Cow::Owned)
+            }
         }
     }
+    fn token_range(&self, indices : Range<usize>) -> Option<Tokens<'_>>
+    {
+        match &self.tokens
+        {
+            TokenTable::Loaded(tokens) => tokens.get(indices).map(|tokens| Tokens::Loaded(tokens.iter())),
+            TokenTable::Lazy(_) if indices.start <= indices.end && indices.end <= self.token_count() => Some(Tokens::Lazy { dict : self, indices }),
+            TokenTable::Lazy(_) => None,
+        }
+    }
+    /// Every token in the token table, in order.
+    pub (crate) fn tokens(&self) -> Tokens<'_>
+    {
+        self.token_range(0..self.token_count()).unwrap()
+    }
+    fn value_tokens(&self, value : u32) -> Option<Tokens<'_>>
+    {
+        self.token_range(Self::value_range(value))
+    }
+    pub (crate) fn dic_get<'a>(&'a self, find : &str) -> Option<Tokens<'a>>
+    {
+        let mut base = self.root();
+        for &byte in find.as_bytes()
+        {
+            base = self.child(base, byte)?;
+        }
+        self.value_tokens(self.output(base)?)
+    }
+    /// Every prefix of `text` that's in the trie, shortest first, in a single walk down the trie.
+    pub (crate) fn common_prefixes<'a, 't>(&'a self, text : &'t str) -> PrefixMatches<'a, 't>
+    {
+        PrefixMatches { dict : self, text, base : Some(self.root()), length : 0 }
+    }
+    /// Every surface in the trie with the range of the token table it points at, which might be out of bounds in a broken file.
+    pub (crate) fn entries(&self) -> Vec<(String, Range<usize>)>
+    {
+        let links : Vec<Link> = (0..((self.links_range.end - self.links_range.start) / 8) as u32).filter_map(|index| self.link(index)).collect();
+        let mut collection = Vec::new();
+        collect_links(&links, self.root(), &mut collection, &[]);
+        collection.into_iter().map(|(surface, value)| (surface, Self::value_range(value))).collect()
+    }
+    pub (crate) fn feature_bytes(&self) -> &[u8]
+    {
+        &self.blob[self.feature_bytes_range.clone()]
+    }
     pub (crate) fn feature_get(&self, offset : u32) -> &str
     {
         let offset = offset as usize;
@@ -181,93 +446,216 @@ impl DartDict {
     }
 }
 
-pub (crate) fn load_mecab_dart_file(blob : Blob) -> Result<DartDict, &'static str> {
-    let mut reader = Cursor::new(&blob);
+/// Iterator returned by [`DartDict::common_prefixes`]. Yields the length in bytes of each match along with its tokens.
+pub (crate) struct PrefixMatches<'a, 't> {
+    dict : &'a DartDict,
+    text : &'t str,
+    // None once the walk has fallen off the trie
+    base : Option<u32>,
+    length : usize,
+}
+
+impl<'a, 't> Iterator for PrefixMatches<'a, 't> {
+    type Item = (usize, Tokens<'a>);
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        while let Some(base) = self.base
+        {
+            let byte = match self.text.as_bytes().get(self.length)
+            {
+                Some(&byte) => byte,
+                None => break
+            };
+            self.length += 1;
+            self.base = self.dict.child(base, byte);
+            // keys that end in the middle of a character can't match any substring of the text
+            if let Some(base) = self.base.filter(|_| self.text.is_char_boundary(self.length))
+            {
+                if let Some(tokens) = self.dict.output(base).and_then(|value| self.dict.value_tokens(value))
+                {
+                    return Some((self.length, tokens));
+                }
+            }
+        }
+        self.base = None;
+        None
+    }
+}
+
+/// Loads a compiled dictionary. If `lazy_tokens` is set, the token table is left in the blob and tokens are decoded as they're looked up instead of being copied out up front.
+pub (crate) fn load_mecab_dart_file(blob : Blob, file : DictFile, lazy_tokens : bool) -> Result<DartDict, LoadError> {
+    let mut reader = FileReader::new(&blob, file);
     let dic_file = &mut reader;
     // magic
-    seek_rel_4(dic_file)?;
+    dic_file.skip_4()?;
     
     // 0x04
-    let version = read_u32(dic_file)?;
+    let version = dic_file.u32()?;
     if version != 0x66
     {
-        return Err("unsupported version");
+        return Err(dic_file.malformed("unsupported version"));
     }
     
     // 0x08
-    seek_rel_4(dic_file)?; // dict type - u32 sys (0), usr (1), unk (2) - we don't care and have no use for the information
+    let dict_type = dic_file.u32()?; // dict type - u32 sys (0), usr (1), unk (2)
     
-    read_u32(dic_file)?; // number of unique somethings; might be unique lexeme surfaces, might be feature strings, we don't need it
+    dic_file.u32()?; // number of unique somethings; might be unique lexeme surfaces, might be feature strings, we don't need it
     // 0x10
     // this information is duplicated in the matrix dic_file and we will ensure that it is consistent
-    let left_contexts  = read_u32(dic_file)?;
-    let right_contexts = read_u32(dic_file)?;
+    let left_contexts  = dic_file.u32()?;
+    let right_contexts = dic_file.u32()?;
     
     // 0x18
-    let linkbytes = read_u32(dic_file)?; // number of bytes used to store the dual-array trie
+    let linkbytes = dic_file.u32()?; // number of bytes used to store the dual-array trie
     if linkbytes%8 != 0
     {
-        return Err("dictionary broken: link table stored with number of bytes that is not a multiple of 8");
+        return Err(dic_file.malformed("dictionary broken: link table stored with number of bytes that is not a multiple of 8"));
     }
-    let tokenbytes = read_u32(dic_file)?; // number of bytes used to store the list of tokens
+    let tokenbytes = dic_file.u32()?; // number of bytes used to store the list of tokens
     if tokenbytes%16 != 0
     {
-        return Err("dictionary broken: token table stored with number of bytes that is not a multiple of 16");
+        return Err(dic_file.malformed("dictionary broken: token table stored with number of bytes that is not a multiple of 16"));
     }
     // 0x20
-    let feature_bytes_count = read_u32(dic_file)? as usize; // number of bytes used to store the feature string pile
-    seek_rel_4(dic_file)?;
+    let feature_bytes_count = dic_file.u32()? as usize; // number of bytes used to store the feature string pile
+    dic_file.skip_4()?;
     
-    let encoding = read_nstr(dic_file, 0x20)?;
+    let encoding = dic_file.nstr(0x20)?;
     if encoding.to_lowercase() != "utf-8"
     {
-        return Err("only UTF-8 dictionaries are supported. stop using legacy encodings for infrastructure!");
+        return Err(dic_file.malformed("only UTF-8 dictionaries are supported. stop using legacy encodings for infrastructure!"));
     }
     
-    let mut links : Vec<Link> = Vec::with_capacity((linkbytes/8) as usize);
-    for _i in 0..(linkbytes/8)
+    dic_file.set_section(Section::LinkTable);
+    let links_location = dic_file.position() as usize;
+    let links_range = links_location..links_location + linkbytes as usize;
+    if links_range.is_empty()
     {
-        links.push(Link::read(dic_file)?);
+        return Err(dic_file.malformed_at(links_location as u64, "dictionary broken: link table is empty"));
     }
+    dic_file.skip(linkbytes as usize)?;
     
-    let mut tokens : Vec<FormatToken> = Vec::with_capacity((tokenbytes/16) as usize);
-    for _i in 0..(tokenbytes/16)
+    dic_file.set_section(Section::TokenTable);
+    let tokens = if lazy_tokens
     {
-        tokens.push(FormatToken::read(dic_file, tokens.len() as u32)?);
+        let tokens_location = dic_file.position() as usize;
+        dic_file.skip(tokenbytes as usize)?;
+        TokenTable::Lazy(tokens_location..tokens_location + tokenbytes as usize)
     }
+    else
+    {
+        let mut tokens : Vec<FormatToken> = Vec::with_capacity((tokenbytes/16) as usize);
+        for _i in 0..(tokenbytes/16)
+        {
+            tokens.push(FormatToken::read(dic_file, tokens.len() as u32)?);
+        }
+        TokenTable::Loaded(tokens)
+    };
     
-    let feature_bytes_location = dic_file.seek(std::io::SeekFrom::Current(0)).unwrap() as usize;
+    dic_file.set_section(Section::FeaturePile);
+    let feature_bytes_location = dic_file.position() as usize;
     let feature_bytes_range = feature_bytes_location..feature_bytes_location + feature_bytes_count;
     let feature_slice = match blob.get(feature_bytes_range.clone()) {
         Some(slice) => slice,
         None => {
-            return Err("dictionary broken: invalid feature bytes range");
+            return Err(dic_file.malformed_at(blob.len() as u64, "dictionary broken: feature pile is cut off"));
         }
     };
-    if std::str::from_utf8(feature_slice).is_err() {
-        return Err("dictionary broken: feature blob is not valid UTF-8");
-    }
-    
-    let dictionary = collect_links_into_map(links);
-    
-    let mut contains_longer = HashSet::with_hasher(BuildNoopHasher::default());
-    for entry in dictionary.keys()
-    {
-        let mut hasher = crate::hasher::Hasher::new();
-        for ch in entry.chars()
-        {
-            hasher.write_u32(ch as u32);
-            contains_longer.insert(hasher.finish());
-        }
+    if let Err(err) = std::str::from_utf8(feature_slice) {
+        return Err(dic_file.malformed_at((feature_bytes_location + err.valid_up_to()) as u64, "dictionary broken: feature blob is not valid UTF-8"));
     }
     
     Ok(DartDict {
-        dict: dictionary,
+        dict_type,
         tokens,
-        contains_longer,
         left_contexts,
         right_contexts,
+        links_range,
         feature_bytes_range,
         blob
     })
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Dict;
+    
+    #[test]
+    fn test_build_links()
+    {
+        let entries : Vec<(&[u8], u32)> = vec!(
+            (b"a", 1),
+            (b"ab", 2),
+            (b"abc", 3),
+            (b"b", 4),
+            (b"bcd", 5),
+            ("これ".as_bytes(), 6),
+        );
+        let links = build_links(&entries).unwrap();
+        let mut collection = Vec::new();
+        collect_links(&links, links[0].base, &mut collection, &[]);
+        collection.sort();
+        assert_eq!(collection, vec!(
+            ("a".to_string(), 1),
+            ("ab".to_string(), 2),
+            ("abc".to_string(), 3),
+            ("b".to_string(), 4),
+            ("bcd".to_string(), 5),
+            ("これ".to_string(), 6),
+        ));
+        
+        assert!(build_links(&[(b"b", 1), (b"a", 2)]).is_err());
+        assert!(build_links(&[(b"a", 1), (b"a", 2)]).is_err());
+    }
+    
+    #[test]
+    fn test_common_prefixes()
+    {
+        let dict = crate::compiler::tests::test_dict();
+        let sys_dic = &dict.sys_dic;
+        let matches : Vec<(usize, usize)> = sys_dic.common_prefixes("いけない").map(|(length, tokens)| (length, tokens.len())).collect();
+        assert_eq!(matches, vec!(("い".len(), 1), ("いけ".len(), 1)));
+        let matches : Vec<usize> = sys_dic.common_prefixes("機械").map(|(_, tokens)| tokens.len()).collect();
+        assert_eq!(matches, vec!(2));
+        assert_eq!(sys_dic.common_prefixes("飛").count(), 0);
+        assert_eq!(sys_dic.common_prefixes("").count(), 0);
+        
+        assert_eq!(sys_dic.dic_get("飛行").map(|tokens| tokens.len()), Some(1));
+        assert!(sys_dic.dic_get("飛").is_none());
+        assert!(sys_dic.dic_get("").is_none());
+        let mut surfaces : Vec<String> = sys_dic.entries().into_iter().map(|(surface, _)| surface).collect();
+        surfaces.sort();
+        assert_eq!(surfaces, vec!("い", "いけ", "け", "これ", "て", "を", "持っ", "機", "飛行"));
+    }
+    
+    #[test]
+    fn test_load_lazy()
+    {
+        use crate::compiler::tests::*;
+        let eager = test_dict();
+        let compiled = compiled_test_dict();
+        let lazy = Dict::load_lazy(Blob::new(compiled.sys_dic), Blob::new(compiled.unk_dic), Blob::new(compiled.matrix), Blob::new(compiled.char_bin)).unwrap();
+        assert_eq!(lazy.feature_schema(), eager.feature_schema());
+        for text in &["これを持っていけ", "飛行機 abc", "噛噛"]
+        {
+            let (lazy_tokens, lazy_cost) = lazy.tokenize(text).unwrap();
+            let (eager_tokens, eager_cost) = eager.tokenize(text).unwrap();
+            assert_eq!(lazy_cost, eager_cost);
+            assert_eq!(lazy_tokens.len(), eager_tokens.len());
+            for (lazy_token, eager_token) in lazy_tokens.iter().zip(&eager_tokens)
+            {
+                assert_eq!(lazy_token.range, eager_token.range);
+                assert_eq!(lazy_token.original_id, eager_token.original_id);
+                assert_eq!(lazy_token.get_feature(&lazy), eager_token.get_feature(&eager));
+            }
+        }
+        assert!(lazy.verify().is_empty());
+        
+        // a cut off token table is still caught at load
+        let compiled = compiled_test_dict();
+        let link_bytes = read_u32(&mut &compiled.sys_dic[0x18..]).unwrap() as usize;
+        let sys_dic = compiled.sys_dic[..0x48 + link_bytes + 8].to_vec();
+        assert!(Dict::load_lazy(Blob::new(sys_dic), Blob::new(compiled.unk_dic), Blob::new(compiled.matrix), Blob::new(compiled.char_bin)).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// The dictionary file a [`LoadError`] is about.
+#[derive(Clone, Copy)]
+#[derive(Debug)]
+#[derive(PartialEq, Eq)]
+pub enum DictFile {
+    SysDic,
+    UnkDic,
+    Matrix,
+    CharBin,
+    UserDic,
+    CharDef,
+    UnkDef,
+    MatrixDef,
+    /// A lexicon CSV file, by its position in the list given to [`crate::CompiledDictionary::compile`] (or, for [`crate::CompiledDictionary::compile_directory`], in file name order).
+    Lexicon(usize),
+    Dicrc,
+}
+
+impl fmt::Display for DictFile {
+    fn fmt(&self, fmt : &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            DictFile::SysDic => write!(fmt, "sys.dic"),
+            DictFile::UnkDic => write!(fmt, "unk.dic"),
+            DictFile::Matrix => write!(fmt, "matrix.bin"),
+            DictFile::CharBin => write!(fmt, "char.bin"),
+            DictFile::UserDic => write!(fmt, "user dictionary"),
+            DictFile::CharDef => write!(fmt, "char.def"),
+            DictFile::UnkDef => write!(fmt, "unk.def"),
+            DictFile::MatrixDef => write!(fmt, "matrix.def"),
+            DictFile::Lexicon(index) => write!(fmt, "lexicon #{}", index),
+            DictFile::Dicrc => write!(fmt, "dicrc"),
+        }
+    }
+}
+
+/// The part of a compiled dictionary file a [`LoadError`] is about.
+#[derive(Clone, Copy)]
+#[derive(Debug)]
+#[derive(PartialEq, Eq)]
+pub enum Section {
+    Header,
+    /// The double-array trie of a .dic file.
+    LinkTable,
+    /// The token table of a .dic file.
+    TokenTable,
+    /// The feature string pile of a .dic file.
+    FeaturePile,
+    /// The category names at the start of char.bin.
+    CategoryNames,
+    /// The per-character data of char.bin.
+    CharacterTable,
+    /// The connection costs of matrix.bin.
+    Costs,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, fmt : &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Section::Header => write!(fmt, "header"),
+            Section::LinkTable => write!(fmt, "link table"),
+            Section::TokenTable => write!(fmt, "token table"),
+            Section::FeaturePile => write!(fmt, "feature pile"),
+            Section::CategoryNames => write!(fmt, "category names"),
+            Section::CharacterTable => write!(fmt, "character table"),
+            Section::Costs => write!(fmt, "connection costs"),
+        }
+    }
+}
+
+/// Why a dictionary couldn't be loaded or compiled.
+#[derive(Debug)]
+pub enum LoadError {
+    /// A compiled file couldn't be read, usually because it's cut off. `offset` is where the failed read started, in bytes from the start of the file.
+    Io { file : DictFile, section : Section, offset : u64, source : io::Error },
+    /// A compiled file has contents that don't make sense at the given offset.
+    Malformed { file : DictFile, section : Section, offset : u64, message : &'static str },
+    /// A line of a text file couldn't be used. Lines are numbered from 1.
+    Syntax { file : DictFile, line : usize, message : &'static str },
+    /// A file is unusable as a whole, or doesn't fit together with the other files.
+    Invalid { file : DictFile, message : &'static str },
+    /// A source file or directory couldn't be read from disk.
+    Source { path : PathBuf, source : io::Error },
+}
+
+impl LoadError {
+    /// The file the error is about, if it's one of the dictionary files.
+    pub fn file(&self) -> Option<DictFile>
+    {
+        match self
+        {
+            LoadError::Io { file, .. } | LoadError::Malformed { file, .. } | LoadError::Syntax { file, .. } | LoadError::Invalid { file, .. } => Some(*file),
+            LoadError::Source { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, fmt : &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            LoadError::Io { file, section, offset, source } => write!(fmt, "{}: failed to read {} at offset 0x{:X}: {}", file, section, offset, source),
+            LoadError::Malformed { file, section, offset, message } => write!(fmt, "{}: {} at offset 0x{:X}: {}", file, section, offset, message),
+            LoadError::Syntax { file, line, message } => write!(fmt, "{}: line {}: {}", file, line, message),
+            LoadError::Invalid { file, message } => write!(fmt, "{}: {}", file, message),
+            LoadError::Source { path, source } => write!(fmt, "{}: {}", path.display(), source),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+    {
+        match self
+        {
+            LoadError::Io { source, .. } | LoadError::Source { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Blob;
+    use crate::Dict;
+    use crate::compiler::tests::*;
+    
+    #[test]
+    fn test_load_errors()
+    {
+        let compiled = compiled_test_dict();
+        let link_bytes = crate::file::read_u32(&mut &compiled.sys_dic[0x18..]).unwrap() as usize;
+        let cut_off = compiled.sys_dic[..0x48 + link_bytes + 20].to_vec();
+        match Dict::load(Blob::new(cut_off), Blob::new(compiled.unk_dic), Blob::new(compiled.matrix), Blob::new(compiled.char_bin))
+        {
+            Err(LoadError::Io { file : DictFile::SysDic, section : Section::TokenTable, offset, .. }) => assert_eq!(offset as usize, 0x48 + link_bytes + 20),
+            _ => panic!("expected an IO error in the token table of sys.dic"),
+        }
+        
+        let compiled = compiled_test_dict();
+        let mut matrix = compiled.matrix.clone();
+        matrix.pop();
+        let result = Dict::load(Blob::new(compiled.sys_dic), Blob::new(compiled.unk_dic), Blob::new(matrix), Blob::new(compiled.char_bin));
+        assert!(matches!(result, Err(LoadError::Malformed { file : DictFile::Matrix, section : Section::Costs, .. })));
+        
+        let mut dict = test_dict();
+        let error = dict.load_user_dictionary(Blob::new("これ,1,1,100,代名詞\nそれ,1,x,100,代名詞")).unwrap_err();
+        assert!(matches!(error, LoadError::Syntax { file : DictFile::UserDic, line : 2, .. }));
+        assert_eq!(error.to_string(), "user dictionary: line 2: invalid right context ID");
+    }
+}
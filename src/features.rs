@@ -0,0 +1,246 @@
+use std::borrow::Cow;
+
+// Splits off the first field of a mecab CSV line, handling double-quoted fields the same way mecab does.
+pub (crate) fn split_csv_field(line : &str) -> (Cow<'_, str>, Option<&str>)
+{
+    let line = line.trim_start_matches([' ', '\t']);
+    if let Some(quoted) = line.strip_prefix('"')
+    {
+        let mut field = String::new();
+        let mut chars = quoted.char_indices().peekable();
+        let mut rest = "";
+        while let Some((index, c)) = chars.next()
+        {
+            if c == '"'
+            {
+                if let Some((_, '"')) = chars.peek()
+                {
+                    chars.next();
+                }
+                else
+                {
+                    rest = &quoted[index + 1..];
+                    break;
+                }
+            }
+            field.push(c);
+        }
+        (Cow::Owned(field), rest.find(',').map(|comma| &rest[comma + 1..]))
+    }
+    else
+    {
+        match line.find(',')
+        {
+            Some(comma) => (Cow::Borrowed(&line[..comma]), Some(&line[comma + 1..])),
+            None => (Cow::Borrowed(line), None)
+        }
+    }
+}
+
+const UNIDIC_FIELDS : &[&str] = &[
+    "pos1", "pos2", "pos3", "pos4", "cType", "cForm", "lForm", "lemma", "orth", "pron", "orthBase", "pronBase", "goshu",
+    "iType", "iForm", "fType", "fForm", "iConType", "fConType", "type", "kana", "kanaBase", "form", "formBase",
+    "aType", "aConType", "aModType", "lid", "lemma_id",
+];
+const UNIDIC_2_1_2_FIELDS : &[&str] = &[
+    "pos1", "pos2", "pos3", "pos4", "cType", "cForm", "lForm", "lemma", "orth", "pron", "orthBase", "pronBase", "goshu",
+    "iType", "iForm", "fType", "fForm", "kana", "kanaBase", "form", "formBase", "iConType", "fConType",
+    "aType", "aConType", "aModType",
+];
+const IPADIC_FIELDS : &[&str] = &["pos1", "pos2", "pos3", "pos4", "cType", "cForm", "lemma", "reading", "pron"];
+const JUMANDIC_FIELDS : &[&str] = &["pos1", "pos2", "cType", "cForm", "lemma", "reading", "semantics"];
+
+/// Names for the fields of a dictionary's feature strings, so that they can be looked up by name with [`Features::get`].
+///
+/// The presets use the field names from UniDic's dicrc. IPADIC and JumanDic use the same names for the fields they have in common with UniDic.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq, Eq)]
+pub struct FeatureSchema {
+    names : Vec<String>,
+}
+
+impl FeatureSchema {
+    /// A schema with the given field names, in order.
+    pub fn new<S : Into<String>>(names : impl IntoIterator<Item = S>) -> Self
+    {
+        FeatureSchema { names : names.into_iter().map(|name| name.into()).collect() }
+    }
+
+    /// pos1, pos2, pos3, pos4, cType, cForm, lForm, lemma, orth, pron, orthBase, pronBase, goshu, iType, iForm, fType, fForm, iConType, fConType, type, kana, kanaBase, form, formBase, aType, aConType, aModType, lid, lemma_id
+    ///
+    /// This is the field order of UniDic 2.3.0 and later. Older versions have fewer fields and put some of them in a different order, see [`FeatureSchema::unidic_2_1_2`].
+    pub fn unidic() -> Self
+    {
+        Self::new(UNIDIC_FIELDS.iter().copied())
+    }
+
+    /// pos1, pos2, pos3, pos4, cType, cForm, lForm, lemma, orth, pron, orthBase, pronBase, goshu, iType, iForm, fType, fForm, kana, kanaBase, form, formBase, iConType, fConType, aType, aConType, aModType
+    ///
+    /// The field order of UniDic 2.1.2.
+    pub fn unidic_2_1_2() -> Self
+    {
+        Self::new(UNIDIC_2_1_2_FIELDS.iter().copied())
+    }
+
+    /// pos1, pos2, pos3, pos4, cType, cForm, lemma, reading, pron
+    pub fn ipadic() -> Self
+    {
+        Self::new(IPADIC_FIELDS.iter().copied())
+    }
+
+    /// pos1, pos2, cType, cForm, lemma, reading, semantics
+    pub fn jumandic() -> Self
+    {
+        Self::new(JUMANDIC_FIELDS.iter().copied())
+    }
+
+    /// Guesses which preset fits a dictionary from the number of fields in its feature strings.
+    ///
+    /// Only field counts that belong to exactly one preset are recognized: 29 for [`FeatureSchema::unidic`], 26 for [`FeatureSchema::unidic_2_1_2`], 9 for [`FeatureSchema::ipadic`] and 7 for [`FeatureSchema::jumandic`].
+    /// Returns `None` for anything else, since guessing wrong would make [`Features::get`] return the wrong fields.
+    pub fn detect<'a>(features : impl IntoIterator<Item = &'a str>) -> Option<Self>
+    {
+        let count = features.into_iter().map(|feature| Features::parse(feature, None).len()).max()?;
+        match count
+        {
+            29 => Some(Self::unidic()),
+            26 => Some(Self::unidic_2_1_2()),
+            9 => Some(Self::ipadic()),
+            7 => Some(Self::jumandic()),
+            _ => None
+        }
+    }
+
+    pub fn names(&self) -> &[String]
+    {
+        &self.names
+    }
+
+    /// Returns the position of the field with the given name.
+    pub fn index_of(&self, name : &str) -> Option<usize>
+    {
+        self.names.iter().position(|field| field == name)
+    }
+}
+
+/// The fields of a feature string, split up like mecab splits up CSV lines. Quoted fields are unquoted.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Features<'a> {
+    fields : Vec<Cow<'a, str>>,
+    schema : Option<&'a FeatureSchema>,
+}
+
+impl<'a> Features<'a> {
+    /// Splits up a feature string. The schema is only needed to look fields up by name.
+    pub fn parse(feature : &'a str, schema : Option<&'a FeatureSchema>) -> Self
+    {
+        let mut fields = Vec::new();
+        let mut rest = Some(feature);
+        while let Some(line) = rest
+        {
+            let (field, next) = split_csv_field(line);
+            fields.push(field);
+            rest = next;
+        }
+        Features { fields, schema }
+    }
+
+    /// Returns the field with the given name in the schema.
+    ///
+    /// Returns `None` if there is no schema, the schema has no such field, or this feature string doesn't have that many fields (e.g. the feature strings of unknown tokens are usually shorter).
+    pub fn get(&self, name : &str) -> Option<&str>
+    {
+        self.field(self.schema?.index_of(name)?)
+    }
+
+    /// Returns the field at the given position.
+    pub fn field(&self, index : usize) -> Option<&str>
+    {
+        self.fields.get(index).map(|field| field.as_ref())
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.fields.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str>
+    {
+        self.fields.iter().map(|field| field.as_ref())
+    }
+
+    pub fn schema(&self) -> Option<&'a FeatureSchema>
+    {
+        self.schema
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_csv_field()
+    {
+        assert_eq!(split_csv_field("a,b,c"), (Cow::Borrowed("a"), Some("b,c")));
+        assert_eq!(split_csv_field("\"a,b\",c"), (Cow::Borrowed("a,b"), Some("c")));
+        assert_eq!(split_csv_field("\"a\"\"b\",c"), (Cow::Borrowed("a\"b"), Some("c")));
+        assert_eq!(split_csv_field("abc"), (Cow::Borrowed("abc"), None));
+    }
+
+    #[test]
+    fn test_features()
+    {
+        let schema = FeatureSchema::unidic();
+        let feature = "動詞,一般,*,*,五段-タ行,連用形-促音便,モツ,持つ,持っ,モッ,持つ,モツ,和,*,*,*,*,*,*,用,モッ,モツ,モッ,モツ,\"1,0\",C1,*,10007,36";
+        let features = Features::parse(feature, Some(&schema));
+        assert_eq!(features.len(), 29);
+        assert_eq!(features.get("pos1"), Some("動詞"));
+        assert_eq!(features.get("lemma"), Some("持つ"));
+        assert_eq!(features.get("aType"), Some("1,0"));
+        assert_eq!(features.get("aConType"), Some("C1"));
+        assert_eq!(features.get("lemma_id"), Some("36"));
+        assert_eq!(features.get("nonexistent"), None);
+        assert_eq!(Features::parse(feature, None).get("lemma"), None);
+
+        let features = Features::parse("補助記号,一般,*,*,*,*", Some(&schema));
+        assert_eq!(features.get("pos2"), Some("一般"));
+        assert_eq!(features.get("lemma"), None);
+        assert_eq!(features.iter().collect::<Vec<_>>(), vec!("補助記号", "一般", "*", "*", "*", "*"));
+
+        assert_eq!(Features::parse("", None).len(), 1);
+        assert_eq!(Features::parse("a,,b,", None).iter().collect::<Vec<_>>(), vec!("a", "", "b", ""));
+
+        assert_eq!(FeatureSchema::detect([feature]), Some(FeatureSchema::unidic()));
+        let old_feature = "動詞,一般,*,*,五段-タ行,連用形-促音便,モツ,持つ,持っ,モッ,持つ,モツ,和,*,*,*,*,モッ,モツ,モッ,モツ,*,*,\"1,0\",C1,*";
+        assert_eq!(FeatureSchema::detect([old_feature]), Some(FeatureSchema::unidic_2_1_2()));
+        let schema = FeatureSchema::unidic_2_1_2();
+        let features = Features::parse(old_feature, Some(&schema));
+        assert_eq!(features.get("kana"), Some("モッ"));
+        assert_eq!(features.get("aType"), Some("1,0"));
+        assert_eq!(features.get("lid"), None);
+        assert_eq!(FeatureSchema::detect(["名詞,普通名詞,一般,*,*,*,ケ,毛,け,ケ,毛,ケ,和,*,*,*,*"]), None);
+        assert_eq!(FeatureSchema::detect(["名詞,一般,*,*,*,*,テスト,テスト,テスト", "名詞,一般,*,*,*,*,*"]), Some(FeatureSchema::ipadic()));
+        assert_eq!(FeatureSchema::detect(["名詞,普通名詞,*,*,人,じん,代表表記:人/じん"]), Some(FeatureSchema::jumandic()));
+        assert_eq!(FeatureSchema::detect(["a,b"]), None);
+        assert_eq!(FeatureSchema::detect([]), None);
+
+        // detected from sys.dic when a dictionary is loaded
+        use crate::compiler::tests::*;
+        let dict = test_dict();
+        assert_eq!(dict.feature_schema(), Some(&FeatureSchema::ipadic()));
+        let lexicon = "持っ,3,3,200,動詞,一般,*,*,五段-タ行,連用形-促音便,モツ,持つ,持っ,モッ,持つ,モツ,和,*,*,*,*,*,*,用,モッ,モツ,モッ,モツ,\"1,0\",C1,*,10007,36";
+        let dict = crate::CompiledDictionary::compile(&[lexicon], MATRIX_DEF, CHAR_DEF, UNK_DEF).unwrap().load().unwrap();
+        assert_eq!(dict.feature_schema(), Some(&FeatureSchema::unidic()));
+        let (tokens, _) = dict.tokenize("持っ").unwrap();
+        assert_eq!(tokens[0].features(&dict).get("lemma"), Some("持つ"));
+        assert_eq!(tokens[0].features(&dict).get("aType"), Some("1,0"));
+    }
+}
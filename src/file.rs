@@ -1,26 +1,24 @@
+use std::io;
+use std::io::Cursor;
 use std::io::Read;
 
-pub (crate) fn read_i16<T : Read>(f : &mut T) -> Result<i16, &'static str>
+use crate::error::*;
+
+pub (crate) fn read_i16<T : Read>(f : &mut T) -> io::Result<i16>
 {
     read_u16(f).map(|val| val as i16)
 }
-pub (crate) fn read_u16<T : Read>(f : &mut T) -> Result<u16, &'static str>
+pub (crate) fn read_u16<T : Read>(f : &mut T) -> io::Result<u16>
 {
     let mut buffer = [0; 2];
-    match f.read_exact(&mut buffer)
-    {
-        Ok(()) => Ok(u16::from_le_bytes(buffer)),
-        _ => Err("IO error")
-    }
+    f.read_exact(&mut buffer)?;
+    Ok(u16::from_le_bytes(buffer))
 }
-pub (crate) fn read_u32<T : Read>(f : &mut T) -> Result<u32, &'static str>
+pub (crate) fn read_u32<T : Read>(f : &mut T) -> io::Result<u32>
 {
     let mut buffer = [0; 4];
-    match f.read_exact(&mut buffer)
-    {
-        Ok(()) => Ok(u32::from_le_bytes(buffer)),
-        _ => Err("IO error")
-    }
+    f.read_exact(&mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
 }
 
 unsafe fn as_byte_slice_mut<T>(slice : &mut [T]) -> &mut [u8]
@@ -31,10 +29,10 @@ unsafe fn as_byte_slice_mut<T>(slice : &mut [T]) -> &mut [u8]
     )
 }
 
-pub (crate) fn read_i16_buffer<T : Read>(f : &mut T, dst : &mut [i16]) -> Result<(), &'static str>
+pub (crate) fn read_i16_buffer<T : Read>(f : &mut T, dst : &mut [i16]) -> io::Result<()>
 {
     let dst_b = unsafe { as_byte_slice_mut(dst) };
-    f.read_exact(dst_b).map_err(|_| "IO error")?;
+    f.read_exact(dst_b)?;
 
     for val in dst.iter_mut()
     {
@@ -54,51 +52,119 @@ fn trim_at_null(mystr : &[u8]) -> &[u8]
     &mystr[..nullpos]
 }
 
-pub (crate) fn read_nstr<T : Read>(f : &mut T, n : usize) -> Result<String, &'static str>
+pub (crate) fn read_nstr<T : Read>(f : &mut T, n : usize) -> io::Result<String>
 {
     let mut buf = vec![0u8; n];
-    
-    match f.read_exact(&mut buf)
-    {
-        Ok(_) =>
-        {
-            let mystr = std::str::from_utf8(trim_at_null(&buf));
-            
-            if let Ok(mystr) = mystr
-            {
-                Ok(mystr.to_string())
-            }
-            else
+    f.read_exact(&mut buf)?;
+    read_str_buffer(&buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+pub (crate) fn read_str_buffer(buf : &[u8]) -> Result<String, std::str::Utf8Error>
+{
+    std::str::from_utf8(trim_at_null(buf)).map(|mystr| mystr.to_string())
+}
+
+// this is way, WAY faster than seeking 4 bytes forward explicitly.
+pub (crate) fn seek_rel_4<T : Read>(f : &mut T) -> io::Result<()>
+{
+    let mut bogus = [0u8; 4];
+    f.read_exact(&mut bogus)
+}
+
+/// Reads a compiled dictionary file, attaching the file, section and offset to errors.
+pub (crate) struct FileReader<'a> {
+    cursor : Cursor<&'a [u8]>,
+    file : DictFile,
+    section : Section,
+    // where the last read started
+    last_offset : u64,
+}
+
+impl<'a> FileReader<'a> {
+    pub (crate) fn new(data : &'a [u8], file : DictFile) -> Self
+    {
+        FileReader { cursor : Cursor::new(data), file, section : Section::Header, last_offset : 0 }
+    }
+    pub (crate) fn set_section(&mut self, section : Section)
+    {
+        self.section = section;
+    }
+    pub (crate) fn position(&self) -> u64
+    {
+        self.cursor.position()
+    }
+    fn read<T>(&mut self, f : impl FnOnce(&mut Cursor<&'a [u8]>) -> io::Result<T>) -> Result<T, LoadError>
+    {
+        self.last_offset = self.cursor.position();
+        f(&mut self.cursor).map_err(|source| LoadError::Io { file : self.file, section : self.section, offset : self.last_offset, source })
+    }
+    pub (crate) fn u16(&mut self) -> Result<u16, LoadError>
+    {
+        self.read(read_u16)
+    }
+    pub (crate) fn i16(&mut self) -> Result<i16, LoadError>
+    {
+        self.read(read_i16)
+    }
+    pub (crate) fn u32(&mut self) -> Result<u32, LoadError>
+    {
+        self.read(read_u32)
+    }
+    pub (crate) fn skip_4(&mut self) -> Result<(), LoadError>
+    {
+        self.read(seek_rel_4)
+    }
+    pub (crate) fn skip(&mut self, count : usize) -> Result<(), LoadError>
+    {
+        self.read(|cursor| {
+            let position = cursor.position() + count as u64;
+            if position > cursor.get_ref().len() as u64
             {
-                Err("Decoding error")
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
             }
-        }
-        _ => Err("IO error")
+            cursor.set_position(position);
+            Ok(())
+        })
     }
-}
-pub (crate) fn read_str_buffer(buf : &[u8]) -> Result<String, &'static str>
-{
-    let mystr = std::str::from_utf8(trim_at_null(buf));
-    
-    if let Ok(mystr) = mystr
+    pub (crate) fn nstr(&mut self, n : usize) -> Result<String, LoadError>
+    {
+        self.read(|cursor| read_nstr(cursor, n))
+    }
+    /// An error about the data that was read last.
+    pub (crate) fn malformed(&self, message : &'static str) -> LoadError
     {
-        Ok(mystr.to_string())
+        self.malformed_at(self.last_offset, message)
     }
-    else
+    pub (crate) fn malformed_at(&self, offset : u64, message : &'static str) -> LoadError
     {
-        Err("UTF-8 decoding error")
+        LoadError::Malformed { file : self.file, section : self.section, offset, message }
     }
 }
 
-// this is way, WAY faster than seeking 4 bytes forward explicitly.
-pub (crate) fn seek_rel_4<T : Read>(f : &mut T) -> Result<(), &'static str>
+// compiled dictionary files always have NUL bytes near the start, text sources never do
+pub (crate) fn is_text(data : &[u8]) -> bool
 {
-    let mut bogus = [0u8; 4];
-    match f.read_exact(&mut bogus)
+    !data.iter().take(0x100).any(|&byte| byte == 0)
+}
+
+/// Decodes the text of a source file, pointing at the line with the first invalid byte on failure.
+pub (crate) fn decode_text(data : &[u8], file : DictFile) -> Result<&str, LoadError>
+{
+    std::str::from_utf8(data).map_err(|err| {
+        let line = data[..err.valid_up_to()].iter().filter(|&&byte| byte == b'\n').count() + 1;
+        LoadError::Syntax { file, line, message : "not valid UTF-8" }
+    })
+}
+
+// whether the data is exactly as large as its header says a matrix.bin file should be
+pub (crate) fn is_matrix_bin(data : &[u8]) -> bool
+{
+    if data.len() < 4
     {
-        Ok(_) => Ok(()),
-        _ => Err("IO error")
+        return false;
     }
+    let left_size  = u16::from_le_bytes([data[0], data[1]]) as usize;
+    let right_size = u16::from_le_bytes([data[2], data[3]]) as usize;
+    data.len() == 4 + left_size * right_size * 2
 }
 
 #[cfg(test)]
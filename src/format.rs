@@ -0,0 +1,427 @@
+use std::fmt;
+use std::fmt::Write;
+use std::ops::Range;
+
+use super::Dict;
+use super::Features;
+use super::LexerToken;
+use super::TokenType;
+
+/// A template given to [`NodeFormat`] that couldn't be parsed.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq, Eq)]
+pub struct FormatError {
+    pub template : String,
+    /// Byte offset of the problem in the template.
+    pub position : usize,
+    pub message : String,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, fmt : &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(fmt, "bad format at byte {} of {:?}: {}", self.position, self.template, self.message)
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+#[derive(Clone)]
+#[derive(Debug)]
+enum Piece {
+    Text(String),
+    /// %m
+    Surface,
+    /// %M
+    SurfaceWithWhitespace,
+    /// %H
+    Feature,
+    /// %f[N,...] and %FC[N,...]
+    Fields(Vec<usize>, char),
+    /// %s
+    Stat,
+    /// %c and %pw
+    WordCost,
+    /// %pC
+    ConnectionCost,
+    /// %pc
+    PathCost,
+    /// %ps
+    Start,
+    /// %pe
+    End,
+    /// %pl
+    Length,
+    /// %pL
+    LengthWithWhitespace,
+    /// %pS
+    Whitespace,
+    /// %pb
+    Best,
+    /// %pP
+    Probability,
+    /// %phl
+    LeftContext,
+    /// %phr
+    RightContext,
+    /// %h
+    Pos,
+    /// %S
+    Sentence,
+    /// %L
+    SentenceLength,
+}
+
+// what the pieces of a template are filled in with for one node
+struct Node<'a> {
+    range : Range<usize>,
+    whitespace_start : usize,
+    features : Option<Features<'a>>,
+    feature : &'a str,
+    stat : u8,
+    contexts : (u16, u16),
+    pos : u16,
+    probability : Option<f64>,
+    word_cost : i64,
+    connection_cost : i64,
+    path_cost : i64,
+}
+
+fn parse_template(template : &str) -> Result<Vec<Piece>, FormatError>
+{
+    let error = |position, message : &str| FormatError { template : template.to_string(), position, message : message.to_string() };
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.char_indices().peekable();
+    while let Some((position, c)) = chars.next()
+    {
+        match c
+        {
+            '\\' =>
+            {
+                let escaped = match chars.next()
+                {
+                    Some((_, 't')) => '\t',
+                    Some((_, 'n')) => '\n',
+                    Some((_, 'r')) => '\r',
+                    Some((_, 's')) => ' ',
+                    Some((_, '0')) => '\0',
+                    Some((_, '\\')) => '\\',
+                    Some((_, other)) => other,
+                    None => return Err(error(position, "template ends with a backslash"))
+                };
+                text.push(escaped);
+            }
+            '%' =>
+            {
+                let directive = match chars.next()
+                {
+                    Some((_, directive)) => directive,
+                    None => return Err(error(position, "template ends with a percent sign"))
+                };
+                let piece = match directive
+                {
+                    '%' =>
+                    {
+                        text.push('%');
+                        continue;
+                    }
+                    'm' => Piece::Surface,
+                    'M' => Piece::SurfaceWithWhitespace,
+                    'H' => Piece::Feature,
+                    's' => Piece::Stat,
+                    'c' => Piece::WordCost,
+                    'S' => Piece::Sentence,
+                    'L' => Piece::SentenceLength,
+                    'h' => Piece::Pos,
+                    'f' | 'F' =>
+                    {
+                        let separator = if directive == 'F'
+                        {
+                            match chars.next()
+                            {
+                                Some((_, separator)) => separator,
+                                None => return Err(error(position, "%F needs a separator"))
+                            }
+                        }
+                        else
+                        {
+                            ','
+                        };
+                        if chars.next_if(|(_, c)| *c == '[').is_none()
+                        {
+                            return Err(error(position, "expected [ after %f or %F"));
+                        }
+                        let mut list = String::new();
+                        loop
+                        {
+                            match chars.next()
+                            {
+                                Some((_, ']')) => break,
+                                Some((_, c)) => list.push(c),
+                                None => return Err(error(position, "missing ] after field numbers"))
+                            }
+                        }
+                        let indices = list.split(',').map(|index| index.trim().parse::<usize>()).collect::<Result<Vec<_>, _>>();
+                        match indices
+                        {
+                            Ok(indices) => Piece::Fields(indices, separator),
+                            Err(_) => return Err(error(position, "field numbers must be a comma-separated list of numbers"))
+                        }
+                    }
+                    'p' =>
+                    {
+                        match chars.next()
+                        {
+                            Some((_, 'w')) => Piece::WordCost,
+                            Some((_, 'C')) => Piece::ConnectionCost,
+                            Some((_, 'c')) => Piece::PathCost,
+                            Some((_, 's')) => Piece::Start,
+                            Some((_, 'e')) => Piece::End,
+                            Some((_, 'l')) => Piece::Length,
+                            Some((_, 'L')) => Piece::LengthWithWhitespace,
+                            Some((_, 'S')) => Piece::Whitespace,
+                            Some((_, 'b')) => Piece::Best,
+                            Some((_, 'P')) => Piece::Probability,
+                            Some((_, 'h')) => match chars.next()
+                            {
+                                Some((_, 'l')) => Piece::LeftContext,
+                                Some((_, 'r')) => Piece::RightContext,
+                                _ => return Err(error(position, "expected %phl or %phr"))
+                            }
+                            _ => return Err(error(position, "unsupported %p directive"))
+                        }
+                    }
+                    _ => return Err(error(position, "unsupported directive"))
+                };
+                if !text.is_empty()
+                {
+                    pieces.push(Piece::Text(std::mem::take(&mut text)));
+                }
+                pieces.push(piece);
+            }
+            _ => text.push(c)
+        }
+    }
+    if !text.is_empty()
+    {
+        pieces.push(Piece::Text(text));
+    }
+    Ok(pieces)
+}
+
+/// Renders tokenized text with mecab-style output format templates, as given to mecab's `--node-format`, `--unk-format`, `--bos-format` and `--eos-format` options.
+///
+/// Supported directives:
+///
+/// - `%m` surface, `%M` surface with the whitespace stripped from before it, `%pS` just that whitespace
+/// - `%H` the whole feature string, `%f[N]` or `%f[N,M,...]` feature fields joined with commas, `%FC[N,M,...]` joined with the character C instead (like mecab, `*` fields are left out when more than one field is asked for)
+/// - `%c` or `%pw` word cost, `%pC` connection cost from the previous node, `%pc` cost of the path up to and including the node
+/// - `%ps` and `%pe` start and end of the surface in bytes, `%pl` and `%pL` its length without and with the whitespace before it
+/// - `%phl` and `%phr` the left and right context IDs, `%h` the part-of-speech ID
+/// - `%s` node status: 0 for dictionary tokens, 1 for unknown tokens, 2 for BOS and 3 for EOS
+/// - `%pP` the token's probability if [`Dict::set_marginal_theta`] is enabled, or nothing otherwise
+/// - `%pb` `*` (every rendered node is on the best path), `%S` the whole sentence, `%L` its length in bytes, `%%` a percent sign
+///
+/// and the escapes `\t`, `\n`, `\r`, `\s` (space), `\0` and `\\`. Feature fields are split with mecab's CSV quoting rules, see [`Features`].
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct NodeFormat {
+    node : Vec<Piece>,
+    unk : Option<Vec<Piece>>,
+    bos : Vec<Piece>,
+    eos : Vec<Piece>,
+}
+
+impl NodeFormat {
+    /// A format with the given node format, mecab's default EOS format (`EOS\n`) and nothing for BOS. Unknown tokens use the node format.
+    pub fn new(node_format : &str) -> Result<Self, FormatError>
+    {
+        Ok(NodeFormat {
+            node : parse_template(node_format)?,
+            unk : None,
+            bos : Vec::new(),
+            eos : parse_template("EOS\\n")?,
+        })
+    }
+
+    /// mecab's default output: surface, tab, feature string.
+    pub fn mecab() -> Self
+    {
+        Self::new("%m\\t%H\\n").unwrap()
+    }
+
+    pub fn set_unk_format(&mut self, unk_format : &str) -> Result<(), FormatError>
+    {
+        self.unk = Some(parse_template(unk_format)?);
+        Ok(())
+    }
+
+    pub fn set_bos_format(&mut self, bos_format : &str) -> Result<(), FormatError>
+    {
+        self.bos = parse_template(bos_format)?;
+        Ok(())
+    }
+
+    pub fn set_eos_format(&mut self, eos_format : &str) -> Result<(), FormatError>
+    {
+        self.eos = parse_template(eos_format)?;
+        Ok(())
+    }
+
+    /// Renders the result of tokenizing `text`, as returned by [`Dict::tokenize`].
+    pub fn render(&self, dict : &Dict, text : &str, tokens : &[LexerToken], total_cost : i64) -> String
+    {
+        let mut output = String::new();
+        self.render_into(&mut output, dict, text, tokens, total_cost);
+        output
+    }
+
+    /// Like [`NodeFormat::render`], but appends to `output`.
+    pub fn render_into(&self, output : &mut String, dict : &Dict, text : &str, tokens : &[LexerToken], total_cost : i64)
+    {
+        let bos = Node { range : 0..0, whitespace_start : 0, features : None, feature : "", stat : 2, contexts : (0, 0), pos : 0, probability : Some(1.0), word_cost : 0, connection_cost : 0, path_cost : 0 };
+        render_pieces(output, &self.bos, text, &bos);
+
+        let mut path_cost = 0;
+        let mut end = 0;
+        for token in tokens
+        {
+            path_cost += token.real_cost;
+            let feature = token.get_feature(dict);
+            let unknown = matches!(token.kind, TokenType::UNK | TokenType::Whitespace);
+            let node = Node {
+                range : token.range.clone(),
+                whitespace_start : end,
+                features : Some(token.features(dict)),
+                feature,
+                stat : if unknown { 1 } else { 0 },
+                contexts : (token.left_context(), token.right_context()),
+                pos : token.pos(),
+                probability : token.probability,
+                word_cost : token.cost,
+                connection_cost : token.real_cost - token.cost,
+                path_cost,
+            };
+            let pieces = match &self.unk
+            {
+                Some(unk) if unknown => unk,
+                _ => &self.node
+            };
+            render_pieces(output, pieces, text, &node);
+            end = token.range.end;
+        }
+
+        let eos = Node { range : text.len()..text.len(), whitespace_start : end, features : None, feature : "", stat : 3, contexts : (0, 0), pos : 0, probability : Some(1.0), word_cost : 0, connection_cost : total_cost - path_cost, path_cost : total_cost };
+        render_pieces(output, &self.eos, text, &eos);
+    }
+}
+
+fn render_pieces(output : &mut String, pieces : &[Piece], text : &str, node : &Node)
+{
+    // writing to a String can't fail
+    for piece in pieces
+    {
+        match piece
+        {
+            Piece::Text(piece) => output.push_str(piece),
+            Piece::Surface => output.push_str(&text[node.range.clone()]),
+            Piece::SurfaceWithWhitespace => output.push_str(&text[node.whitespace_start..node.range.end]),
+            Piece::Whitespace => output.push_str(&text[node.whitespace_start..node.range.start]),
+            Piece::Feature => output.push_str(node.feature),
+            Piece::Fields(indices, separator) =>
+            {
+                let fields = indices.iter().filter_map(|&index| node.features.as_ref()?.field(index));
+                for (i, field) in fields.filter(|field| indices.len() == 1 || *field != "*").enumerate()
+                {
+                    if i > 0
+                    {
+                        output.push(*separator);
+                    }
+                    output.push_str(field);
+                }
+            }
+            Piece::Stat => write!(output, "{}", node.stat).unwrap(),
+            Piece::WordCost => write!(output, "{}", node.word_cost).unwrap(),
+            Piece::ConnectionCost => write!(output, "{}", node.connection_cost).unwrap(),
+            Piece::PathCost => write!(output, "{}", node.path_cost).unwrap(),
+            Piece::Start => write!(output, "{}", node.range.start).unwrap(),
+            Piece::End => write!(output, "{}", node.range.end).unwrap(),
+            Piece::Length => write!(output, "{}", node.range.len()).unwrap(),
+            Piece::LengthWithWhitespace => write!(output, "{}", node.range.end - node.whitespace_start).unwrap(),
+            Piece::Best => output.push('*'),
+            Piece::LeftContext => write!(output, "{}", node.contexts.0).unwrap(),
+            Piece::RightContext => write!(output, "{}", node.contexts.1).unwrap(),
+            Piece::Pos => write!(output, "{}", node.pos).unwrap(),
+            Piece::Probability =>
+            {
+                if let Some(probability) = node.probability
+                {
+                    write!(output, "{}", probability).unwrap();
+                }
+            }
+            Piece::Sentence => output.push_str(text),
+            Piece::SentenceLength => write!(output, "{}", text.len()).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::tests::test_dict;
+
+    #[test]
+    fn test_node_format()
+    {
+        let dict = test_dict();
+        let text = "これを 持っ";
+        let (tokens, cost) = dict.tokenize(text).unwrap();
+
+        assert_eq!(NodeFormat::mecab().render(&dict, text, &tokens, cost), "\
+これ\t代名詞,*,*,*,*,*,コレ,此れ,これ
+を\t助詞,格助詞,*,*,*,*,ヲ,を,を
+持っ\t動詞,一般,*,*,五段-タ行,連用形-促音便,モツ,持つ,持っ
+EOS
+");
+
+        let mut format = NodeFormat::new("%m/%f[7]/%F-[0,1,2]/%ps-%pe[%M]\\s").unwrap();
+        format.set_bos_format("[%S] ").unwrap();
+        format.set_eos_format("%L\\n").unwrap();
+        assert_eq!(format.render(&dict, text, &tokens, cost), "[これを 持っ] これ/此れ/代名詞/0-6[これ] を/を/助詞-格助詞/6-9[を] 持っ/持つ/動詞-一般/10-16[ 持っ] 16\n");
+
+        let format = NodeFormat::new("%phl/%phr/%h ").unwrap();
+        assert_eq!(format.render(&dict, text, &tokens, cost), "1/1/0 2/2/0 3/3/0 EOS\n");
+
+        let mut format = NodeFormat::new("%c+%pC=%pc ").unwrap();
+        format.set_eos_format("%pC=%pc").unwrap();
+        let rendered = format.render(&dict, text, &tokens, cost);
+        let mut path_cost = 0;
+        for (token, node) in tokens.iter().zip(rendered.split(' '))
+        {
+            path_cost += token.real_cost;
+            assert_eq!(node, format!("{}+{}={}", token.cost, token.real_cost - token.cost, path_cost));
+        }
+        assert!(rendered.ends_with(&format!("={}", cost)));
+
+        // quoted fields come out unquoted, and unknown tokens can have their own format
+        let mut dict = test_dict();
+        dict.load_user_dictionary(crate::Blob::new("ほげ,1,1,100,\"名詞,固有\",*")).unwrap();
+        let (tokens, cost) = dict.tokenize("ほげ噛").unwrap();
+        let mut format = NodeFormat::new("%f[0] %s\\n").unwrap();
+        format.set_unk_format("?%m %s\\n").unwrap();
+        assert_eq!(format.render(&dict, "ほげ噛", &tokens, cost), "名詞,固有 0\n?噛 1\nEOS\n");
+
+        let mut dict = test_dict();
+        let format = NodeFormat::new("%pP ").unwrap();
+        assert_eq!(format.render(&dict, "これ", &dict.tokenize("これ").unwrap().0, 0), " EOS\n");
+        dict.set_marginal_theta(Some(0.01));
+        assert_eq!(format.render(&dict, "これ", &dict.tokenize("これ").unwrap().0, 0), "1 EOS\n");
+
+        assert!(NodeFormat::new("%f[a]").is_err());
+        assert!(NodeFormat::new("%f[1").is_err());
+        assert!(NodeFormat::new("%q").is_err());
+        assert_eq!(NodeFormat::new("ab%").unwrap_err().position, 2);
+    }
+}
@@ -0,0 +1,166 @@
+use std::ops::Range;
+
+use super::Dict;
+use super::TokenType;
+use super::Features;
+
+/// A single candidate token in a [`Lattice`].
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct LatticeNode {
+    /// The range, in bytes, to which this node corresponds to in the original text.
+    pub range : Range<usize>,
+    /// Origin of the node. See [`crate::LexerToken::kind`].
+    pub kind : TokenType,
+    /// Cost of the word itself, without any connection costs.
+    pub cost : i64,
+    /// Context ID used when connecting to the node on the left.
+    pub left_context : u16,
+    /// Context ID used when connecting to the node on the right.
+    pub right_context : u16,
+    /// Unique identifier of what specific lexeme realization this is. See [`crate::LexerToken::original_id`].
+    pub original_id : u32,
+    /// Location of the node's feature string. See [`LatticeNode::get_feature`].
+    pub feature_offset : u32,
+    /// The lowest cost of any path from the start of the text up to and including this node,
+    /// or `None` if no path reaches it.
+    pub reach_cost : Option<i64>,
+    /// The probability of this node being on the path, if [`Dict::set_marginal_theta`] is enabled.
+    pub probability : Option<f64>,
+}
+
+impl LatticeNode {
+    /// Returns the text to which this node corresponds to in the original text.
+    pub fn get_text<'a>(&self, whole_text : &'a str) -> &'a str
+    {
+        &whole_text[self.range.clone()]
+    }
+
+    /// Returns the feature string corresponding to this node.
+    pub fn get_feature<'a>(&self, dict : &'a Dict) -> &'a str
+    {
+        dict.read_feature_string_by_source(self.kind, self.feature_offset)
+    }
+
+    /// Returns the feature string corresponding to this node, split up into its fields. See [`crate::LexerToken::features`].
+    pub fn features<'a>(&self, dict : &'a Dict) -> Features<'a>
+    {
+        Features::parse(self.get_feature(dict), dict.feature_schema())
+    }
+}
+
+/// Every candidate token the lattice builder considered for a string, along with the lowest-cost path through them.
+///
+/// Built by [`Dict::build_lattice`]. The nodes are sorted by where they start in the text.
+pub struct Lattice {
+    pub(crate) nodes : Vec<LatticeNode>,
+    pub(crate) best_path : Vec<usize>,
+    pub(crate) best_cost : Option<i64>,
+}
+
+impl Lattice {
+    /// Returns every node in the lattice, sorted by where they start in the text.
+    pub fn nodes(&self) -> &[LatticeNode]
+    {
+        &self.nodes
+    }
+
+    /// Returns the nodes that start at the given byte offset in the text.
+    pub fn nodes_starting_at(&self, start : usize) -> &[LatticeNode]
+    {
+        let first = self.nodes.partition_point(|node| node.range.start < start);
+        let end = self.nodes.partition_point(|node| node.range.start <= start);
+        &self.nodes[first..end]
+    }
+
+    /// Iterates over the nodes grouped by the byte offset they start at, in order.
+    pub fn iter_by_start(&self) -> LatticeStarts<'_>
+    {
+        LatticeStarts { nodes : &self.nodes }
+    }
+
+    /// Returns the indices into [`Lattice::nodes`] of the lowest-cost path, in order.
+    pub fn best_path(&self) -> &[usize]
+    {
+        &self.best_path
+    }
+
+    /// Returns the total cost of the lowest-cost path, or `None` if there is no path.
+    pub fn best_cost(&self) -> Option<i64>
+    {
+        self.best_cost
+    }
+}
+
+/// Iterator returned by [`Lattice::iter_by_start`].
+pub struct LatticeStarts<'a> {
+    nodes : &'a [LatticeNode],
+}
+
+impl<'a> Iterator for LatticeStarts<'a> {
+    type Item = (usize, &'a [LatticeNode]);
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        let start = self.nodes.first()?.range.start;
+        let length = self.nodes.iter().take_while(|node| node.range.start == start).count();
+        let (group, rest) = self.nodes.split_at(length);
+        self.nodes = rest;
+        Some((start, group))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::tests::test_dict;
+
+    #[test]
+    fn test_build_lattice()
+    {
+        let dict = test_dict();
+        let text = "これを持っていけ";
+        let (tokens, cost) = dict.tokenize(text).unwrap();
+        let lattice = dict.build_lattice(text);
+        let nodes = lattice.nodes();
+
+        assert!(nodes.windows(2).all(|pair| pair[0].range.start <= pair[1].range.start));
+        assert!(nodes.len() > tokens.len());
+
+        // groups cover every node once, in order, and agree with nodes_starting_at
+        let mut count = 0;
+        let mut previous = None;
+        for (start, group) in lattice.iter_by_start()
+        {
+            assert!(previous < Some(start));
+            assert!(!group.is_empty());
+            assert!(group.iter().all(|node| node.range.start == start));
+            assert_eq!(lattice.nodes_starting_at(start).len(), group.len());
+            count += group.len();
+            previous = Some(start);
+        }
+        assert_eq!(count, nodes.len());
+        assert!(lattice.nodes_starting_at(0).iter().any(|node| node.get_text(text) == "これ"));
+        assert!(lattice.nodes_starting_at(1).is_empty());
+        assert!(lattice.nodes_starting_at(text.len()).is_empty());
+
+        // the best path is the one tokenize picks
+        assert_eq!(lattice.best_cost(), Some(cost));
+        let best : Vec<_> = lattice.best_path().iter().map(|&index| &nodes[index]).collect();
+        assert_eq!(best.len(), tokens.len());
+        for (node, token) in best.iter().zip(&tokens)
+        {
+            assert_eq!(node.range, token.range);
+            assert_eq!(node.original_id, token.original_id);
+            assert_eq!(node.get_feature(&dict), token.get_feature(&dict));
+            assert!(node.reach_cost.is_some());
+        }
+        let last = best.last().unwrap();
+        assert_eq!(last.reach_cost.unwrap() + dict.access_matrix(last.right_context, 0) as i64, cost);
+
+        let lattice = dict.build_lattice("");
+        assert!(lattice.nodes().is_empty());
+        assert!(lattice.best_path().is_empty());
+        assert_eq!(lattice.best_cost(), None);
+        assert!(lattice.iter_by_start().next().is_none());
+        assert!(lattice.nodes_starting_at(0).is_empty());
+    }
+}
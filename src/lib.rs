@@ -1,7 +1,7 @@
 #![allow(clippy::suspicious_else_formatting)]
 use std::io::Cursor;
-use std::io::Read;
 use std::io::Seek;
+use std::borrow::Cow;
 use std::ops::Range;
 use std::ops::Deref;
 
@@ -9,13 +9,9 @@ use std::str;
 
 #[cfg(not(feature = "hashbrown"))]
 pub(crate) use std::collections::HashMap;
-#[cfg(not(feature = "hashbrown"))]
-pub(crate) use std::collections::HashSet;
 
 #[cfg(feature = "hashbrown")]
 pub(crate) use hashbrown::HashMap;
-#[cfg(feature = "hashbrown")]
-pub(crate) use hashbrown::HashSet;
 
 mod blob;
 mod file;
@@ -23,14 +19,38 @@ mod dart;
 mod unkchar;
 mod userdict;
 mod pathing;
-mod hasher;
+mod lattice;
+mod constraints;
+mod compiler;
+mod error;
+mod verify;
+mod features;
+mod stream;
+mod profile;
+mod matrixcache;
+mod format;
+mod score;
+#[cfg(feature = "parallel")]
+mod batch;
 
 use self::file::*;
 use self::dart::*;
 use self::unkchar::*;
 use self::userdict::*;
+use self::matrixcache::MatrixCache;
 
 pub use self::blob::Blob;
+pub use self::lattice::{Lattice, LatticeNode, LatticeStarts};
+pub use self::constraints::Constraints;
+pub use self::compiler::CompiledDictionary;
+pub use self::error::{LoadError, DictFile, Section};
+pub use self::verify::Problem;
+pub use self::features::{Features, FeatureSchema};
+pub use self::stream::{StreamSentence, TokenStream};
+pub use self::userdict::UserEntry;
+pub use self::profile::EdgeProfile;
+pub use self::format::{NodeFormat, FormatError};
+pub use self::score::{SegmentationScore, SpanScore};
 
 #[derive(Clone)]
 #[derive(Debug)]
@@ -48,19 +68,19 @@ pub (crate) struct FormatToken {
 
 impl FormatToken {
     #[allow(clippy::cast_lossless)]
-    fn read<T : Read + std::io::Seek>(sysdic : &mut T, original_id : u32) -> Result<FormatToken, &'static str>
+    fn read(sysdic : &mut FileReader, original_id : u32) -> Result<FormatToken, LoadError>
     {
         let ret = FormatToken
-        { left_context : read_u16(sysdic)?,
-          right_context : read_u16(sysdic)?,
-          pos : read_u16(sysdic)?,
-          cost : read_i16(sysdic)? as i64,
+        { left_context : sysdic.u16()?,
+          right_context : sysdic.u16()?,
+          pos : sysdic.u16()?,
+          cost : sysdic.i16()? as i64,
           original_id,
-          feature_offset : read_u32(sysdic)?,
+          feature_offset : sysdic.u32()?,
         };
         
         // seek away a u32 of padding
-        seek_rel_4(sysdic)?;
+        sysdic.skip_4()?;
         
         Ok(ret)
     }
@@ -75,12 +95,29 @@ impl FormatToken {
 pub enum TokenType {
     /// Token came from a mecab dictionary.
     Normal,
-    /// Token came from a user dictionary.
-    User,
+    /// Token came from a user dictionary. The number is the dictionary's index; see [`Dict::user_dictionary_name`].
+    User(u16),
     /// Token over section of text not covered by dictionary (unknown).
     UNK,
     /// Used internally for virtual beginning-of-string and end-of-string tokens. Not exposed to outside functions.
     BOS,
+    /// A run of 0x20 whitespace that was stripped from between tokens. Only emitted when [`Dict::set_whitespace_tokens`] is enabled. Has the feature string of the SPACE (or DEFAULT) unknown token.
+    Whitespace,
+}
+
+/// Where the cost of a token in a tokenization comes from. See [`LexerToken::cost_breakdown`].
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+pub struct CostBreakdown {
+    /// Cost of the word itself. Same as [`LexerToken::cost`].
+    pub word : i64,
+    /// Connection cost from the previous token, or from the beginning of the string for the first token.
+    pub connection : i64,
+    /// Connection cost to the end of the string. Only set for the last token that came out of pathfinding, so not for [`TokenType::Whitespace`] tokens after it.
+    pub end_connection : Option<i64>,
 }
 
 #[derive(Clone)]
@@ -91,7 +128,7 @@ pub struct LexerToken {
     /// Used internally during lattice pathfinding.
     right_context : u16,
     
-    /// I don't know what this is.
+    /// Part-of-speech ID. See [`LexerToken::pos`].
     pos  : u16,
     /// Used internally during lattice pathfinding.
     pub cost : i64,
@@ -110,9 +147,47 @@ pub struct LexerToken {
     pub original_id : u32,
 
     pub feature_offset : u32,
+    
+    /// The probability of this token being part of the tokenization, taking every possible path through the lattice into account. Only filled in when [`Dict::set_marginal_theta`] is enabled, and never for [`TokenType::Whitespace`] tokens.
+    pub probability : Option<f64>,
+    
+    /// Connection cost to the end of the string, for the last token of a tokenization.
+    end_connection_cost : Option<i64>,
 }
 
 impl LexerToken {
+    /// The context ID used for the connection cost to the token on the left, i.e. the right edge of a cost in matrix.def.
+    pub fn left_context(&self) -> u16
+    {
+        self.left_context
+    }
+
+    /// The context ID used for the connection cost to the token on the right, i.e. the left edge of a cost in matrix.def.
+    pub fn right_context(&self) -> u16
+    {
+        self.right_context
+    }
+
+    /// The part-of-speech ID of the token, what mecab calls `posid`.
+    ///
+    /// mecab-dict-index fills it in from the dictionary's pos-id.def. Dictionaries compiled without one, including by [`CompiledDictionary`], have 0 here. notmecab doesn't use it for anything.
+    pub fn pos(&self) -> u16
+    {
+        self.pos
+    }
+
+    /// Splits up what this token adds to the total cost of its tokenization.
+    ///
+    /// `word + connection` is [`LexerToken::real_cost`], and the total cost returned by [`Dict::tokenize`] is the sum of the real costs of the tokens plus the last token's `end_connection`. The costs are the same connection matrix lookups that the pathfinding used.
+    pub fn cost_breakdown(&self) -> CostBreakdown
+    {
+        CostBreakdown {
+            word : self.cost,
+            connection : self.real_cost - self.cost,
+            end_connection : self.end_connection_cost,
+        }
+    }
+
     /// Returns the text to which this token corresponds to in the original text.
     ///
     /// The `whole_text` is the original string for which you've
@@ -132,6 +207,14 @@ impl LexerToken {
     {
         dict.read_feature_string(self)
     }
+
+    /// Returns the feature string corresponding to this token, split up into its fields.
+    ///
+    /// Fields can be looked up by name if the dictionary has a [`FeatureSchema`]; see [`Dict::set_feature_schema`].
+    pub fn features<'a>(&self, dict : &'a Dict) -> Features<'a>
+    {
+        Features::parse(self.get_feature(dict), dict.feature_schema())
+    }
 }
 
 struct EdgeInfo {
@@ -143,6 +226,8 @@ struct EdgeInfo {
     fast_edge_left_edges : usize,
     fast_matrix_cache : Vec<i16>,
     
+    lru_cache : Option<MatrixCache>,
+    
     blob : Blob,
 }
 
@@ -156,6 +241,7 @@ impl EdgeInfo {
             fast_edge_map_right : Vec::new(),
             fast_edge_left_edges : 0,
             fast_matrix_cache : Vec::new(),
+            lru_cache : None,
             blob
         }
     }
@@ -195,9 +281,12 @@ pub struct Dict {
     sys_dic : DartDict,
     unk_dic : DartDict,
     unk_data : UnkChar,
-    user_dic : Option<UserDict>,
+    // in the order they were added; later ones take priority
+    user_dics : Vec<(String, UserDictionary)>,
     
     use_space_stripping : bool,
+    use_whitespace_tokens : bool,
+    marginal_theta : Option<f64>,
     use_unk_forced_processing : bool,
     use_unk_greedy_grouping : bool,
     use_unk_prefix_grouping : bool,
@@ -205,50 +294,113 @@ pub struct Dict {
     left_edges : u16,
     right_edges : u16,
     
+    feature_schema : Option<FeatureSchema>,
+    
     matrix : EdgeInfo
 }
 
 impl Dict {
     /// Load sys.dic and matrix.bin files into memory and prepare the data that's stored in them to be used by the parser.
     ///
-    /// Returns a Dict or, on error, a string describing an error that prevented the Dict from being created.
+    /// Returns a Dict or, on error, a [`LoadError`] saying which file is broken and where.
     ///
     /// Only supports UTF-8 mecab dictionaries with a version number of 0x66.
     ///
     /// Ensures that sys.dic and matrix.bin have compatible connection matrix sizes.
-    #[allow(clippy::cast_lossless)]
+    ///
+    /// The unknown character data can be given either compiled (unk.dic and char.bin) or as the text of mecab's unk.def and char.def source files, which are then compiled in memory. Likewise, the connection matrix can be given as the text of matrix.def instead of matrix.bin. Which one was given is detected automatically.
     pub fn load(
         sysdic : Blob,
         unkdic : Blob,
         matrix : Blob,
         unkchar : Blob,
-    ) -> Result<Dict, &'static str>
+    ) -> Result<Dict, LoadError>
+    {
+        Self::load_with_token_table(sysdic, unkdic, matrix, unkchar, false)
+    }
+    /// Like [`Dict::load`], but leaves the token table of sys.dic in its blob and reads each token out of it when it's looked up, instead of copying the whole table to the heap up front.
+    ///
+    /// Tokenizing is slightly slower, but with a memory-mapped sys.dic (see [`Blob::open`]) almost nothing of it gets copied into private memory, so many processes can share one copy of the dictionary through the page cache.
+    pub fn load_lazy(
+        sysdic : Blob,
+        unkdic : Blob,
+        matrix : Blob,
+        unkchar : Blob,
+    ) -> Result<Dict, LoadError>
     {
-        let sys_dic = load_mecab_dart_file(sysdic)?;
-        let unk_dic = load_mecab_dart_file(unkdic)?;
-        let unk_data = load_char_bin(&mut Cursor::new(unkchar))?;
+        Self::load_with_token_table(sysdic, unkdic, matrix, unkchar, true)
+    }
+    #[allow(clippy::cast_lossless)]
+    fn load_with_token_table(
+        sysdic : Blob,
+        unkdic : Blob,
+        matrix : Blob,
+        unkchar : Blob,
+        lazy_tokens : bool,
+    ) -> Result<Dict, LoadError>
+    {
+        let sys_dic = load_mecab_dart_file(sysdic, DictFile::SysDic, lazy_tokens)?;
+        let unk_data = if is_text(&unkchar)
+        {
+            load_char_def(decode_text(&unkchar, DictFile::CharDef)?)?
+        }
+        else
+        {
+            load_char_bin(&unkchar)?
+        };
+        let unk_dic = if is_text(&unkdic)
+        {
+            let unk_def = decode_text(&unkdic, DictFile::UnkDef)?;
+            let compiled = crate::compiler::compile_unk_def(unk_def, &unk_data.names, sys_dic.left_contexts, sys_dic.right_contexts)?;
+            load_mecab_dart_file(Blob::new(compiled), DictFile::UnkDef, false)?
+        }
+        else
+        {
+            load_mecab_dart_file(unkdic, DictFile::UnkDic, false)?
+        };
+        
+        let matrix = if is_text(&matrix) && !is_matrix_bin(&matrix)
+        {
+            let matrix_def = decode_text(&matrix, DictFile::MatrixDef)?;
+            Blob::new(crate::compiler::compile_matrix_def(matrix_def)?)
+        }
+        else
+        {
+            matrix
+        };
         
-        let mut matrix_cursor = Cursor::new(matrix.as_ref());
-        let left_edges  = read_u16(&mut matrix_cursor)?;
-        let right_edges = read_u16(&mut matrix_cursor)?;
+        let mut matrix_reader = FileReader::new(&matrix, DictFile::Matrix);
+        let left_edges  = matrix_reader.u16()?;
+        let right_edges = matrix_reader.u16()?;
+        matrix_reader.set_section(Section::Costs);
+        if matrix.len() < 4 + left_edges as usize * right_edges as usize * 2
+        {
+            return Err(matrix_reader.malformed_at(matrix.len() as u64, "matrix.bin is cut off"));
+        }
         
         if sys_dic.left_contexts != left_edges as u32 || sys_dic.right_contexts != right_edges as u32
         {
-            return Err("sys.dic and matrix.bin have inconsistent left/right edge counts");
+            return Err(LoadError::Invalid { file : DictFile::Matrix, message : "sys.dic and matrix.bin have inconsistent left/right edge counts" });
         }
         
+        let feature_schema = FeatureSchema::detect(sys_dic.tokens().take(64).map(|token| sys_dic.feature_get(token.feature_offset)));
+        
         Ok(Dict {
             sys_dic,
             unk_dic,
             unk_data,
-            user_dic: None,
+            user_dics: Vec::new(),
             use_space_stripping : true,
+            use_whitespace_tokens : false,
+            marginal_theta : None,
             use_unk_forced_processing : true,
             use_unk_greedy_grouping : true,
             use_unk_prefix_grouping : true,
             left_edges,
             right_edges,
             
+            feature_schema,
+            
             matrix : EdgeInfo::new(matrix)
         })
     }
@@ -257,12 +409,129 @@ impl Dict {
     /// The first four fields are the surface, left context ID, right context ID, and cost of the token.
     ///
     /// Everything past the fourth comma is treated as pure text and is the token's feature string. It is itself normally a list of comma-separated fields with the same format as the feature strings of the main mecab dictionary.
-    pub fn load_user_dictionary(&mut self, userdic : Blob) -> Result<(), &'static str>
+    ///
+    /// A user dictionary compiled by mecab-dict-index (a .dic file with the user dictionary type) can be given instead of the CSV text. Which one was given is detected automatically. Compiled user dictionaries must have the same connection matrix size as sys.dic.
+    ///
+    /// Replaces all user dictionaries loaded so far with this one, under the name "user". To use more than one, see [`Dict::add_user_dictionary`].
+    pub fn load_user_dictionary(&mut self, userdic : Blob) -> Result<(), LoadError>
+    {
+        let user_dic = self.read_user_dictionary(userdic)?;
+        self.user_dics = vec!(("user".to_string(), user_dic));
+        Ok(())
+    }
+    /// Adds a user dictionary (in either of the formats [`Dict::load_user_dictionary`] takes) on top of the ones loaded so far, under a name of your choice. Returns its index, which is what [`TokenType::User`] tokens from it carry.
+    ///
+    /// All user dictionaries are searched when tokenizing. If more than one has entries for the same surface, only the entries of the one added last are used, so e.g. a per-user dictionary added after a company-wide one overrides it.
+    ///
+    /// Fails if a user dictionary with the same name was already added.
+    pub fn add_user_dictionary(&mut self, name : &str, userdic : Blob) -> Result<u16, LoadError>
+    {
+        self.check_user_dictionary_name(name)?;
+        let user_dic = self.read_user_dictionary(userdic)?;
+        self.user_dics.push((name.to_string(), user_dic));
+        Ok((self.user_dics.len() - 1) as u16)
+    }
+    /// Adds an empty user dictionary on top of the ones loaded so far, for [`Dict::add_user_entry`] to put words in. Returns its index.
+    pub fn create_user_dictionary(&mut self, name : &str) -> Result<u16, LoadError>
+    {
+        self.check_user_dictionary_name(name)?;
+        self.user_dics.push((name.to_string(), UserDictionary::Text(UserDict::new())));
+        Ok((self.user_dics.len() - 1) as u16)
+    }
+    /// Returns the index of the user dictionary with the given name.
+    pub fn user_dictionary_index(&self, name : &str) -> Option<u16>
+    {
+        self.user_dics.iter().position(|(other, _)| other == name).map(|index| index as u16)
+    }
+    /// Returns the name of the user dictionary with the given index, e.g. the one in a [`TokenType::User`].
+    pub fn user_dictionary_name(&self, index : u16) -> Option<&str>
     {
-        let mut userdic = Cursor::new(userdic);
-        self.user_dic = Some(UserDict::load(&mut userdic)?);
+        self.user_dics.get(index as usize).map(|(name, _)| name.as_str())
+    }
+    fn check_user_dictionary_name(&self, name : &str) -> Result<(), LoadError>
+    {
+        if self.user_dictionary_index(name).is_some()
+        {
+            return Err(LoadError::Invalid { file : DictFile::UserDic, message : "a user dictionary with this name was already added" });
+        }
+        if self.user_dics.len() > u16::MAX as usize
+        {
+            return Err(LoadError::Invalid { file : DictFile::UserDic, message : "too many user dictionaries" });
+        }
         Ok(())
     }
+    fn read_user_dictionary(&self, userdic : Blob) -> Result<UserDictionary, LoadError>
+    {
+        if is_text(&userdic)
+        {
+            let mut userdic = Cursor::new(userdic);
+            return Ok(UserDictionary::Text(UserDict::load(&mut userdic)?));
+        }
+        
+        let user_dic = load_mecab_dart_file(userdic, DictFile::UserDic, false)?;
+        if user_dic.dict_type != 1
+        {
+            return Err(LoadError::Malformed { file : DictFile::UserDic, section : Section::Header, offset : 0x08, message : "compiled user dictionary is not of the user dictionary type" });
+        }
+        if user_dic.left_contexts != self.left_edges as u32 || user_dic.right_contexts != self.right_edges as u32
+        {
+            return Err(LoadError::Invalid { file : DictFile::UserDic, message : "user dictionary and matrix.bin have inconsistent left/right edge counts" });
+        }
+        Ok(UserDictionary::Compiled(user_dic))
+    }
+    fn editable_user_dic(&mut self, dictionary : u16) -> Result<&mut UserDict, LoadError>
+    {
+        match self.user_dics.get_mut(dictionary as usize)
+        {
+            Some((_, UserDictionary::Text(user_dic))) => Ok(user_dic),
+            Some((_, UserDictionary::Compiled(_))) => Err(LoadError::Invalid { file : DictFile::UserDic, message : "compiled user dictionaries can't be edited" }),
+            None => Err(LoadError::Invalid { file : DictFile::UserDic, message : "no user dictionary with this index" }),
+        }
+    }
+    fn check_user_entry(&self, entry : &UserEntry) -> Result<(), LoadError>
+    {
+        if entry.surface.is_empty()
+        {
+            return Err(LoadError::Invalid { file : DictFile::UserDic, message : "entries must have a surface" });
+        }
+        if entry.right_context >= self.left_edges || entry.left_context >= self.right_edges
+        {
+            return Err(LoadError::Invalid { file : DictFile::UserDic, message : "context ID out of range of matrix.bin" });
+        }
+        Ok(())
+    }
+    /// Adds a word to the user dictionary with the given index (see [`Dict::create_user_dictionary`]). Takes effect on the next call to any tokenizing method.
+    ///
+    /// Returns the ID of the new entry, which is also the `original_id` of tokens made from it. Entries loaded from CSV have the (zero-based) line they were on as their ID.
+    ///
+    /// Fails if the entry has an empty surface or context IDs that don't fit the connection matrix, or if the user dictionary doesn't exist or is a compiled one.
+    pub fn add_user_entry(&mut self, dictionary : u16, entry : UserEntry) -> Result<u32, LoadError>
+    {
+        self.check_user_entry(&entry)?;
+        Ok(self.editable_user_dic(dictionary)?.add(entry))
+    }
+    /// Replaces the entry with the given ID in the given user dictionary. Returns false if there is no such entry.
+    ///
+    /// Feature offsets of tokens that were made from the old version of the entry stay valid.
+    pub fn update_user_entry(&mut self, dictionary : u16, id : u32, entry : UserEntry) -> Result<bool, LoadError>
+    {
+        self.check_user_entry(&entry)?;
+        Ok(self.editable_user_dic(dictionary)?.update(id, entry))
+    }
+    /// Removes the entry with the given ID from the given user dictionary. Returns false if there is no such entry.
+    pub fn remove_user_entry(&mut self, dictionary : u16, id : u32) -> bool
+    {
+        self.editable_user_dic(dictionary).map(|user_dic| user_dic.remove(id)).unwrap_or(false)
+    }
+    /// Returns the entry with the given ID in the given user dictionary. Entries of compiled user dictionaries can't be looked up.
+    pub fn user_entry(&self, dictionary : u16, id : u32) -> Option<UserEntry>
+    {
+        match self.user_dics.get(dictionary as usize)
+        {
+            Some((_, UserDictionary::Text(user_dic))) => user_dic.entry(id),
+            _ => None
+        }
+    }
     /// Returns the feature string belonging to a LexerToken.
     pub fn read_feature_string(&self, token : &LexerToken) -> &str
     {
@@ -273,11 +542,32 @@ impl Dict {
     {
         match kind
         {
-            TokenType::UNK => self.unk_dic.feature_get(offset),
+            TokenType::UNK | TokenType::Whitespace => self.unk_dic.feature_get(offset),
             TokenType::Normal | TokenType::BOS => self.sys_dic.feature_get(offset),
-            TokenType::User => self.user_dic.as_ref().unwrap().feature_get(offset),
+            TokenType::User(index) => self.user_dics[index as usize].1.feature_get(offset),
         }
     }
+    /// Applies lines in the format of mecab's char.def on top of the loaded character categories, e.g. `0x1F300..0x1FAFF EMOJI` to give emoji a category of their own.
+    ///
+    /// Category definition lines add new categories or replace how existing ones behave; code point range lines assign categories to characters, overriding earlier assignments. Ranges may refer to any category defined in the loaded dictionary or in `char_def` itself. Unknown tokens are only built for categories that have entries in unk.dic, and characters in other categories fall back to DEFAULT.
+    ///
+    /// char.bin only covers code points below U+FFFF, and everything above that is treated as DEFAULT unless it's assigned a category here, or in the char.def text the dictionary was loaded with.
+    pub fn apply_char_def(&mut self, char_def : &str) -> Result<(), LoadError>
+    {
+        self.unk_data.apply_char_def(char_def)
+    }
+    /// Sets the names of the fields of this dictionary's feature strings, used by [`LexerToken::features`].
+    ///
+    /// By default, the schema is guessed from the feature strings in sys.dic when the dictionary is loaded. See [`FeatureSchema::detect`].
+    pub fn set_feature_schema(&mut self, schema : Option<FeatureSchema>)
+    {
+        self.feature_schema = schema;
+    }
+    /// Returns the feature schema used by [`LexerToken::features`], if one was set or detected.
+    pub fn feature_schema(&self) -> Option<&FeatureSchema>
+    {
+        self.feature_schema.as_ref()
+    }
     /// Optional feature for applications that need to use as little memory as possible without accessing disk constantly. "Undocumented". May be removed at any time for any reason.
     ///
     /// Does nothing if the prepare_full_matrix_cache has already been called.
@@ -335,6 +625,7 @@ impl Dict {
         matrix.fast_edge_map_right = Vec::new();
         matrix.fast_edge_left_edges = 0;
         matrix.fast_matrix_cache = Vec::new();
+        matrix.lru_cache = None;
         
         let size = self.left_edges as usize * self.right_edges as usize;
         let mut new_fast_cache = vec!(0; size);
@@ -345,6 +636,24 @@ impl Dict {
         
         matrix.fast_matrix_cache = new_fast_cache;
     }
+    /// Cache the connection matrix in small blocks that are read in the first time one of their costs is looked up, keeping about `memory_budget` bytes of them, counting the cache's bookkeeping for each block, and dropping the least recently used blocks when it fills up. "Undocumented".
+    ///
+    /// Unlike prepare_fast_matrix_cache, this doesn't need to know ahead of time which connections are common, so hot connections get close to full-cache speed on dictionaries like unidic whose whole matrix is too big to load. The cache is shared by every thread tokenizing with this Dict. Costs covered by prepare_fast_matrix_cache are still looked up there first.
+    ///
+    /// Does nothing if prepare_full_matrix_cache has already been called. A budget too small for a single block turns the cache off again.
+    pub fn prepare_lru_matrix_cache(&mut self, memory_budget : usize)
+    {
+        if self.matrix.full_cache_enabled
+        {
+            return;
+        }
+        self.matrix.lru_cache = MatrixCache::new(memory_budget);
+    }
+    /// Returns how many connection cost lookups did and didn't find their block in the cache set up by prepare_lru_matrix_cache, or None if it isn't enabled.
+    pub fn lru_matrix_cache_stats(&self) -> Option<(u64, u64)>
+    {
+        self.matrix.lru_cache.as_ref().map(|cache| cache.stats())
+    }
 
     /// Tokenizes a string by creating a lattice of possible tokens over it
     /// and finding the lowest-cost path thought that lattice.
@@ -374,39 +683,292 @@ impl Dict {
     /// same `Cache` object across multiple invocations for increased efficiency.
     pub fn tokenize_with_cache(&self, cache : &mut Cache, text : &str, output : &mut Vec<LexerToken>) -> Result<i64, TokenizeError>
     {
-        fn take_memory<'a, 'b>(vec : &mut Vec<Token<'a>>) -> Vec<Token<'b>>
+        let mut tokens = take_memory(&mut cache.tokens);
+        generate_potential_tokens(self, text, &mut tokens);
+
+        let result = self.find_best_path(&mut cache.pathing_cache, &tokens, output);
+
+        cache.tokens = take_memory(&mut tokens);
+        self.fill_whitespace(text, output, result)
+    }
+
+    /// Tokenizes everything read from `reader`, one line at a time, reusing a single [`Cache`].
+    ///
+    /// Token ranges are byte offsets into the whole stream. See [`TokenStream`] for how lines are split up.
+    pub fn tokenize_stream<R : std::io::BufRead>(&self, reader : R) -> TokenStream<'_, R>
+    {
+        TokenStream::new(self, reader)
+    }
+
+    /// Tokenizes a string like [`Dict::tokenize`], but only allows tokenizations that satisfy the given constraints.
+    ///
+    /// See [`Dict::tokenize_constrained_with_cache`] for more details.
+    pub fn tokenize_constrained(&self, text : &str, constraints : &Constraints) -> Result<(Vec<LexerToken>, i64), TokenizeError>
+    {
+        let mut cache = Cache::new();
+        let mut tokens = Vec::new();
+        self.tokenize_constrained_with_cache(&mut cache, text, constraints, &mut tokens).map(|cost| (tokens, cost))
+    }
+
+    /// Tokenizes a string like [`Dict::tokenize_with_cache`], but only allows tokenizations that satisfy the given constraints.
+    ///
+    /// Candidate tokens that cross a required boundary, start or end at a forbidden boundary, or break up a
+    /// required token are dropped from the lattice before pathfinding. Required tokens that the dictionaries
+    /// don't have are filled in with unknown tokens, and so are positions where every candidate was dropped.
+    ///
+    /// Returns an error if the constraints can't all be satisfied at once, which includes a required boundary or token being past the end of the text.
+    pub fn tokenize_constrained_with_cache(&self, cache : &mut Cache, text : &str, constraints : &Constraints, output : &mut Vec<LexerToken>) -> Result<i64, TokenizeError>
+    {
+        let mut tokens = take_memory(&mut cache.tokens);
+
+        let result = if generate_constrained_tokens(self, text, constraints, &mut tokens)
+        {
+            self.find_best_path(&mut cache.pathing_cache, &tokens, output)
+        }
+        else
+        {
+            output.clear();
+            Err(TokenizeError { _dummy: () })
+        };
+
+        cache.tokens = take_memory(&mut tokens);
+        self.fill_whitespace(text, output, result)
+    }
+
+    fn find_best_path(&self, cache : &mut crate::pathing::Cache, tokens : &[Token], output : &mut Vec<LexerToken>) -> Result<i64, TokenizeError>
+    {
+        let (path, total_cost) = crate::pathing::shortest_path(
+            cache,
+            tokens.len(),
+            |index| tokens[index].rank,
+            |index| tokens[index].range.end as u32,
+            |left, right| self.cost_between(&tokens[left], &tokens[right]),
+            |index| self.cost_from_start(&tokens[index]),
+            |index| self.cost_to_end(&tokens[index])
+        );
+
+        output.clear();
+        output.extend(path.iter().map(|&index| (&tokens[index as usize]).into()));
+        self.fill_real_costs(output);
+
+        if path.is_empty()
+        {
+            return Err(TokenizeError { _dummy: () });
+        }
+
+        if self.marginal_theta.is_some()
+        {
+            let path : Vec<u32> = path.to_vec();
+            self.compute_marginals(cache, tokens);
+            for (token, &index) in output.iter_mut().zip(&path)
+            {
+                token.probability = cache.marginal(index as usize);
+            }
+        }
+
+        Ok(total_cost)
+    }
+
+    // Replaces the results of the last search in the cache with the probability of each token, if enabled.
+    fn compute_marginals(&self, cache : &mut crate::pathing::Cache, tokens : &[Token])
+    {
+        if let Some(theta) = self.marginal_theta
+        {
+            crate::pathing::marginals(
+                cache,
+                tokens.len(),
+                theta,
+                |index| tokens[index].rank,
+                |index| tokens[index].range.end as u32,
+                |left, right| self.cost_between(&tokens[left], &tokens[right]),
+                |index| self.cost_from_start(&tokens[index]),
+                |index| self.cost_to_end(&tokens[index])
+            );
+        }
+    }
+
+    /// Tokenizes a string and returns up to `count` of the lowest-cost paths through the lattice,
+    /// each with its total cost, in ascending order of cost.
+    ///
+    /// See [`Dict::tokenize_nbest_with_cache`] for more details.
+    pub fn tokenize_nbest(&self, text : &str, count : usize) -> Result<Vec<(Vec<LexerToken>, i64)>, TokenizeError>
+    {
+        let mut cache = Cache::new();
+        let mut paths = Vec::new();
+        self.tokenize_nbest_with_cache(&mut cache, text, count, &mut paths).map(|_| paths)
+    }
+
+    /// Tokenizes a string and returns up to `count` of the lowest-cost paths through the lattice,
+    /// each with its total cost, in ascending order of cost.
+    ///
+    /// If successful the contents of `output` will be replaced with the paths. The first path
+    /// always has the same cost as the one returned by [`Dict::tokenize_with_cache`], though
+    /// when several paths tie for the lowest cost it's not defined which of them comes first.
+    /// Fewer than `count` paths are returned if the lattice doesn't have that many.
+    /// A `count` of 0 succeeds without returning any paths or tokenizing anything.
+    ///
+    /// If unsuccessful the `output` will be cleared.
+    pub fn tokenize_nbest_with_cache(&self, cache : &mut Cache, text : &str, count : usize, output : &mut Vec<(Vec<LexerToken>, i64)>) -> Result<(), TokenizeError>
+    {
+        output.clear();
+        if count == 0
+        {
+            return Ok(());
+        }
+
+        let mut tokens = take_memory(&mut cache.tokens);
+        generate_potential_tokens(self, text, &mut tokens);
+
+        crate::pathing::shortest_paths(
+            &mut cache.pathing_cache,
+            tokens.len(),
+            count,
+            |index| tokens[index].rank,
+            |index| tokens[index].range.end as u32,
+            |left, right| self.cost_between(&tokens[left], &tokens[right]),
+            |index| self.cost_from_start(&tokens[index]),
+            |index| self.cost_to_end(&tokens[index]),
+            |path, total_cost| {
+                let mut path_tokens : Vec<LexerToken> = path.iter().map(|&index| (&tokens[index as usize]).into()).collect();
+                self.fill_real_costs(&mut path_tokens);
+                let _ = self.fill_whitespace(text, &mut path_tokens, Ok(total_cost));
+                output.push((path_tokens, total_cost));
+            }
+        );
+
+        cache.tokens = take_memory(&mut tokens);
+        if output.is_empty()
         {
-            vec.clear();
-            // This is safe since we cleared the vector, so the inner lifetime doesn't matter.
-            let mut vec: &mut Vec<Token<'b>> = unsafe { std::mem::transmute(vec) };
-            let mut out = Vec::new();
-            std::mem::swap(&mut out, &mut vec);
-            out
+            return Err(TokenizeError { _dummy: () });
         }
 
+        Ok(())
+    }
+
+    /// Builds the lattice of every candidate token over a string, without throwing it away after pathfinding.
+    ///
+    /// See [`Dict::build_lattice_with_cache`] for more details.
+    pub fn build_lattice(&self, text : &str) -> Lattice
+    {
+        let mut cache = Cache::new();
+        self.build_lattice_with_cache(&mut cache, text)
+    }
+
+    /// Builds the lattice of every candidate token over a string, without throwing it away after pathfinding.
+    ///
+    /// The lattice contains the same nodes that [`Dict::tokenize_with_cache`] searches through, each with
+    /// its word cost, context IDs and the lowest cost of reaching it from the start of the text, plus
+    /// the lowest-cost path itself.
+    pub fn build_lattice_with_cache(&self, cache : &mut Cache, text : &str) -> Lattice
+    {
         let mut tokens = take_memory(&mut cache.tokens);
         generate_potential_tokens(self, text, &mut tokens);
 
         let (path, total_cost) = crate::pathing::shortest_path(
             &mut cache.pathing_cache,
             tokens.len(),
-            |index| tokens[index].rank as u32,
+            |index| tokens[index].rank,
             |index| tokens[index].range.end as u32,
-            |left, right| {
-                let right_token = &tokens[right];
-                let left_token = &tokens[left];
-                right_token.cost as i64 + self.access_matrix(left_token.right_context, right_token.left_context) as i64
-            },
-            |index| {
-                let right_token = &tokens[index];
-                right_token.cost as i64 + self.access_matrix(0, right_token.left_context) as i64
-            },
-            |index| self.access_matrix(tokens[index].right_context, 0) as i64
+            |left, right| self.cost_between(&tokens[left], &tokens[right]),
+            |index| self.cost_from_start(&tokens[index]),
+            |index| self.cost_to_end(&tokens[index])
         );
+        let best_path : Vec<usize> = path.iter().map(|&index| index as usize).collect();
+        let best_cost = if best_path.is_empty() { None } else { Some(total_cost) };
+        self.compute_marginals(&mut cache.pathing_cache, &tokens);
 
-        output.clear();
-        output.extend(path.iter().map(|&index| (&tokens[index as usize]).into()));
+        let nodes = tokens.iter().enumerate().map(|(index, token)| LatticeNode {
+            range : token.range.clone(),
+            kind : token.kind,
+            cost : token.cost,
+            left_context : token.left_context,
+            right_context : token.right_context,
+            original_id : token.original_id,
+            feature_offset : token.feature_offset,
+            reach_cost : cache.pathing_cache.cost_for_node(index),
+            probability : cache.pathing_cache.marginal(index),
+        }).collect();
+
+        cache.tokens = take_memory(&mut tokens);
+        Lattice { nodes, best_path, best_cost }
+    }
+
+    fn cost_between(&self, left : &FormatToken, right : &FormatToken) -> i64
+    {
+        right.cost + self.access_matrix(left.right_context, right.left_context) as i64
+    }
+
+    fn cost_from_start(&self, right : &FormatToken) -> i64
+    {
+        right.cost + self.access_matrix(0, right.left_context) as i64
+    }
+
+    fn cost_to_end(&self, left : &FormatToken) -> i64
+    {
+        self.access_matrix(left.right_context, 0) as i64
+    }
+
+    // Puts Whitespace tokens over the text that space stripping left out of the output, if enabled.
+    // Text made of nothing but spaces becomes a single Whitespace token instead of failing.
+    fn fill_whitespace(&self, text : &str, output : &mut Vec<LexerToken>, result : Result<i64, TokenizeError>) -> Result<i64, TokenizeError>
+    {
+        if !self.use_whitespace_tokens || !self.use_space_stripping
+        {
+            return result;
+        }
+        let result = match result
+        {
+            Err(_) if !text.is_empty() && text.bytes().all(|byte| byte == b' ') => Ok(0),
+            Err(err) => return Err(err),
+            Ok(cost) => Ok(cost),
+        };
+        
+        let mut filled = Vec::with_capacity(output.len() + 1);
+        let mut end = 0;
+        for token in output.drain(..)
+        {
+            if token.range.start > end
+            {
+                filled.push(self.whitespace_token(end..token.range.start));
+            }
+            end = token.range.end;
+            filled.push(token);
+        }
+        if end < text.len()
+        {
+            filled.push(self.whitespace_token(end..text.len()));
+        }
+        *output = filled;
+        result
+    }
+
+    fn whitespace_token(&self, range : Range<usize>) -> LexerToken
+    {
+        let unknown = self.unk_dic.dic_get("SPACE").and_then(|mut tokens| tokens.next())
+            .or_else(|| self.unk_dic.dic_get("DEFAULT").and_then(|mut tokens| tokens.next()));
+        let (left_context, right_context, pos, original_id, feature_offset) = match unknown
+        {
+            Some(token) => (token.left_context, token.right_context, token.pos, token.original_id, token.feature_offset),
+            None => (0, 0, 0, 0, 0),
+        };
+        LexerToken
+        {
+            left_context,
+            right_context,
+            pos,
+            cost : 0,
+            real_cost : 0,
+            range,
+            kind : TokenType::Whitespace,
+            original_id,
+            feature_offset,
+            probability : None,
+            end_connection_cost : None
+        }
+    }
 
+    fn fill_real_costs(&self, output : &mut [LexerToken])
+    {
         for i in 0..output.len()
         {
             let left_context = if i == 0 { 0 } else { output[i - 1].right_context };
@@ -414,14 +976,10 @@ impl Dict {
             let edge_cost =  self.access_matrix(left_context, right_context);
             output[i].real_cost = output[i].cost + edge_cost as i64;
         }
-
-        cache.tokens = take_memory(&mut tokens);
-        if path.is_empty()
+        if let Some(last) = output.last_mut()
         {
-            return Err(TokenizeError { _dummy: () });
+            last.end_connection_cost = Some(self.access_matrix(last.right_context, 0) as i64);
         }
-
-        Ok(total_cost)
     }
 
     #[allow(clippy::cast_lossless)]
@@ -446,6 +1004,11 @@ impl Dict {
         }
 
         let location = self.left_edges as u32 * right as u32 + left as u32;
+        
+        if let Some(cache) = &matrix.lru_cache
+        {
+            return cache.get(&matrix.blob, location as usize);
+        }
 
         // the 4 is for the two u16s at the beginning that specify the shape of the matrix
         let offset = 4 + location as usize * 2;
@@ -456,13 +1019,37 @@ impl Dict {
     ///
     /// Enabled by default.
     ///
-    /// When enabled, spaces are virtually added to the front of the next token/tokens during lattice construction. This has the effect of turning 0x20 whitespace sequences into forced separators without affecting connection costs, but makes it slightly more difficult to reconstruct the exact original text from the output of the parser.
+    /// When enabled, spaces are virtually added to the front of the next token/tokens during lattice construction. This has the effect of turning 0x20 whitespace sequences into forced separators without affecting connection costs, but makes it slightly more difficult to reconstruct the exact original text from the output of the parser. Enable [`Dict::set_whitespace_tokens`] to get the whitespace back as tokens of its own.
     pub fn set_space_stripping(&mut self, setting : bool) -> bool
     {
         let prev = self.use_space_stripping;
         self.use_space_stripping = setting;
         prev
     }
+    /// Set whether the whitespace removed by space stripping is put back into the output as [`TokenType::Whitespace`] tokens. Returns the previous value of the setting.
+    ///
+    /// Disabled by default. Has no effect if space stripping is disabled, since the whitespace is tokenized normally then.
+    ///
+    /// When enabled, the ranges of the output tokens cover the whole input text with no gaps, so concatenating their text rebuilds the input exactly. The Whitespace tokens have no cost and don't take part in pathfinding, so the other tokens and the total cost are the same as with the setting disabled. Text consisting of nothing but spaces becomes a single Whitespace token instead of failing to tokenize.
+    pub fn set_whitespace_tokens(&mut self, setting : bool) -> bool
+    {
+        let prev = self.use_whitespace_tokens;
+        self.use_whitespace_tokens = setting;
+        prev
+    }
+    /// Set whether, and how, the probability of each token is computed, like mecab's `-m` option. Returns the previous value of the setting.
+    ///
+    /// Disabled (`None`) by default.
+    ///
+    /// When enabled, every path through the lattice is weighted by `exp(-theta * cost)`, and the probability of a token is the share of the total weight of the paths that go through it, worked out with the forward-backward algorithm. [`Dict::tokenize`] and [`Dict::tokenize_constrained`] then fill in [`LexerToken::probability`] for the tokens of the best path, and [`Dict::build_lattice`] fills in [`LatticeNode::probability`] for every node. A low probability means that other tokenizations come close to the chosen one.
+    ///
+    /// A smaller theta spreads the probability more evenly over competing paths. Since word and connection costs in mecab dictionaries are usually in the hundreds or thousands, useful values are well below 1. Tokenizing is noticeably slower when enabled, since the lattice has to be walked twice more.
+    pub fn set_marginal_theta(&mut self, theta : Option<f64>) -> Option<f64>
+    {
+        let prev = self.marginal_theta;
+        self.marginal_theta = theta;
+        prev
+    }
     /// Set whether support for forced unknown token processing is enabled. Returns the previous value of the setting.
     ///
     /// Enabled by default.
@@ -504,17 +1091,27 @@ impl Dict {
     }
 }
 
+fn take_memory<'a, 'b>(vec : &mut Vec<Token<'a>>) -> Vec<Token<'b>>
+{
+    vec.clear();
+    // This is safe since we cleared the vector, so the inner lifetime doesn't matter.
+    let mut vec: &mut Vec<Token<'b>> = unsafe { std::mem::transmute(vec) };
+    let mut out = Vec::new();
+    std::mem::swap(&mut out, &mut vec);
+    out
+}
+
 #[derive(Debug)]
 struct Token<'a>
 {
     rank : u32,
     range : Range<usize>,
     kind : TokenType,
-    format_token : &'a FormatToken
+    format_token : Cow<'a, FormatToken>
 }
 
 impl<'a> Token<'a> {
-    fn new(format_token : &'a FormatToken, rank : usize, range : Range<usize>, kind : TokenType) -> Self
+    fn new(format_token : Cow<'a, FormatToken>, rank : usize, range : Range<usize>, kind : TokenType) -> Self
     {
         Token {
             rank : rank as u32,
@@ -548,7 +1145,9 @@ impl<'a> From<&'a Token<'a>> for LexerToken
             range : token.range.clone(),
             kind : token.kind,
             original_id : token.original_id,
-            feature_offset : token.feature_offset
+            feature_offset : token.feature_offset,
+            probability : None,
+            end_connection_cost : None
         }
     }
 }
@@ -569,12 +1168,9 @@ fn generate_potential_tokens_at<'a>(dict : &'a Dict, text : &str, mut start : us
         space_count = 0;
     }
 
-    let mut index_iter = text[start..].char_indices();
-    let mut end = start;
     let first_char =
-        if let Some((_, c)) = index_iter.next()
+        if let Some(c) = text[start..].chars().next()
         {
-            end += c.len_utf8();
             c
         }
         else
@@ -582,50 +1178,39 @@ fn generate_potential_tokens_at<'a>(dict : &'a Dict, text : &str, mut start : us
             return space_count;
         };
 
-    // find all tokens starting at this point in the string
-    let mut hasher = crate::hasher::Hasher::new();
-    hasher.write_u32(first_char as u32);
-    loop
+    // find all tokens starting at this point in the string, with a single walk down each trie
+    // system tokens go before user tokens of the same length, and shorter tokens before longer ones
+    // a user dictionary hides the entries for the same surface in the ones added before it
+    let mut user_matches = Vec::new();
+    for (index, (_, user_dic)) in dict.user_dics.iter().enumerate().rev()
     {
-        let substring : &str = &text[start..end];
-        let hash = hasher.finish();
-        let mut any = false;
-        if dict.sys_dic.may_contain(hash)
+        for (length, matching_tokens) in user_dic.common_prefixes(&text[start..])
         {
-            any = true;
-            if let Some(matching_tokens) = dict.sys_dic.dic_get(&substring)
+            if !user_matches.iter().any(|(other, _, _)| *other == length)
             {
-                let tokens = matching_tokens.into_iter()
-                    .map(|token| Token::new(token, rank, start..end, TokenType::Normal));
-                output.extend(tokens);
+                user_matches.push((length, index as u16, matching_tokens));
             }
         }
-        if dict.user_dic.as_ref().map(|x| x.may_contain(substring)).unwrap_or(false)
-        {
-            any = true;
-            if let Some(matching_tokens) = dict.user_dic.as_ref().and_then(|user_dic| user_dic.dic_get(&substring))
-            {
-                let tokens = matching_tokens.into_iter()
-                    .map(|token| Token::new(token, rank, start..end, TokenType::User));
-                output.extend(tokens);
-            }
-        }
-
-        if !any
-        {
-            break;
-        }
-
-        if let Some((_, c)) = index_iter.next()
-        {
-            hasher.write_u32(c as u32);
-            end += c.len_utf8();
-        }
-        else
+    }
+    user_matches.sort_by_key(|(length, _, _)| *length);
+    let mut user_matches = user_matches.into_iter().peekable();
+    let mut push_user_tokens = |output : &mut Vec<Token<'a>>, max_length : usize| {
+        while let Some((length, index, matching_tokens)) = user_matches.next_if(|(length, _, _)| *length <= max_length)
         {
-            break;
+            let tokens = matching_tokens
+                .map(|token| Token::new(token, rank, start..start + length, TokenType::User(index)));
+            output.extend(tokens);
         }
+    };
+    for (length, matching_tokens) in dict.sys_dic.common_prefixes(&text[start..])
+    {
+        push_user_tokens(output, length - 1);
+        let tokens = matching_tokens
+            .map(|token| Token::new(token, rank, start..start + length, TokenType::Normal));
+        output.extend(tokens);
+        push_user_tokens(output, length);
     }
+    push_user_tokens(output, usize::MAX);
 
     // build unknown tokens if appropriate
     let start_type = &dict.unk_data.get_type(first_char);
@@ -667,11 +1252,11 @@ fn generate_potential_tokens_at<'a>(dict : &'a Dict, text : &str, mut start : us
             {
                 if do_greedy
                 {
-                    output.push(Token::new(token, rank, start..unk_end, TokenType::UNK));
+                    output.push(Token::new(token.clone(), rank, start..unk_end, TokenType::UNK));
                 }
                 for end in unk_indices[0..prefix_len].iter()
                 {
-                    output.push(Token::new(token, rank, start..*end, TokenType::UNK));
+                    output.push(Token::new(token.clone(), rank, start..*end, TokenType::UNK));
                 }
             }
         }
@@ -685,9 +1270,9 @@ fn generate_potential_tokens_at<'a>(dict : &'a Dict, text : &str, mut start : us
             return;
         }
 
-        if let Some(default_tokens) = dict.unk_dic.dic_get(name)
+        if let Some(mut default_tokens) = dict.unk_dic.dic_get(name)
         {
-            if let Some(first_token) = default_tokens.iter().next()
+            if let Some(first_token) = default_tokens.next()
             {
                 output.push(Token::new(first_token, rank, start..start + first_char_len, TokenType::UNK));
             }
@@ -720,9 +1305,92 @@ fn generate_potential_tokens<'a>(dict : &'a Dict, text : &str, output : &mut Vec
     }
 }
 
+// Like generate_potential_tokens, but drops tokens that break the constraints and fills in required tokens
+// and positions left without any tokens. Returns false if the constraints leave no way through the text.
+fn generate_constrained_tokens<'a>(dict : &'a Dict, text : &str, constraints : &Constraints, output : &mut Vec<Token<'a>>) -> bool
+{
+    if !constraints.fits_within(text.len())
+    {
+        return false;
+    }
+
+    let mut end_of_text = 0;
+    let mut skip_until_after = 0;
+    for i in 0..=text.len()
+    {
+        if i < skip_until_after || !text.is_char_boundary(i)
+        {
+            continue;
+        }
+
+        let first = output.len();
+        let skipnext = generate_potential_tokens_at(dict, text, i, output);
+        skip_until_after = i+skipnext;
+        if output.len() == first
+        {
+            continue;
+        }
+
+        let start = output[first].range.start;
+        end_of_text = output[first..].iter().map(|token| token.range.end).fold(end_of_text, std::cmp::max);
+
+        let mut kept = first;
+        for index in first..output.len()
+        {
+            let token = &output[index];
+            if constraints.allows_range(&token.range)
+               && constraints.allows_feature(&token.range, dict.read_feature_string_by_source(token.kind, token.feature_offset))
+            {
+                output.swap(kept, index);
+                kept += 1;
+            }
+        }
+        output.truncate(kept);
+
+        let first_char = text[start..].chars().next().unwrap();
+        let start_type = dict.unk_data.get_type(first_char);
+        let unknown_tokens = || dict.unk_dic.dic_get(&start_type.name).into_iter().chain(dict.unk_dic.dic_get("DEFAULT")).flatten();
+
+        for (span, prefix) in constraints.required_tokens_at(start)
+        {
+            if output[first..].iter().any(|token| token.range == *span) || !constraints.allows_range(span)
+            {
+                continue;
+            }
+            for token in unknown_tokens()
+            {
+                let feature = dict.unk_dic.feature_get(token.feature_offset);
+                if prefix.as_ref().map(|prefix| feature.starts_with(prefix.as_str())).unwrap_or(true)
+                {
+                    output.push(Token::new(token, i, span.clone(), TokenType::UNK));
+                }
+            }
+        }
+
+        if output.len() == first && constraints.allows_boundary(start)
+        {
+            if let Some(token) = unknown_tokens().next()
+            {
+                let feature = dict.unk_dic.feature_get(token.feature_offset);
+                let fallback_end = text[start..].char_indices()
+                    .map(|(offset, c)| start + offset + c.len_utf8())
+                    .find(|&end| constraints.allows_range(&(start..end)) && constraints.allows_feature(&(start..end), feature));
+                if let Some(end) = fallback_end
+                {
+                    output.push(Token::new(token, i, start..end, TokenType::UNK));
+                }
+            }
+        }
+    }
+
+    output.first().map(|token| token.rank == 0).unwrap_or(false)
+        && output.iter().any(|token| token.range.end == end_of_text)
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::File;
+    use std::io::Read;
     use super::*;
     
     fn assert_implements_sync<T>() where T: Sync {}
@@ -883,5 +1551,264 @@ mod tests {
             }
         }
     }
+    
+    #[test]
+    fn test_whitespace_tokens()
+    {
+        let mut dict = crate::compiler::tests::test_dict();
+        let text = "  これを  持っていけ ";
+        let (tokens, cost) = dict.tokenize(text).unwrap();
+        assert_eq!(tokens.iter().map(|token| token.get_text(text)).collect::<String>(), "これを持っていけ");
+        
+        assert!(!dict.set_whitespace_tokens(true));
+        let (lossless, lossless_cost) = dict.tokenize(text).unwrap();
+        assert_eq!(lossless_cost, cost);
+        assert_eq!(lossless.iter().map(|token| token.get_text(text)).collect::<String>(), text);
+        let whitespace : Vec<&str> = lossless.iter().filter(|token| token.kind == TokenType::Whitespace).map(|token| token.get_text(text)).collect();
+        assert_eq!(whitespace, vec!("  ", "  ", " "));
+        assert_eq!(lossless.iter().map(|token| token.real_cost).sum::<i64>(), tokens.iter().map(|token| token.real_cost).sum::<i64>());
+        assert_eq!(lossless[0].get_feature(&dict), "空白,*,*,*,*,*");
+        for window in lossless.windows(2)
+        {
+            assert_eq!(window[0].range.end, window[1].range.start);
+        }
+        
+        let (only_spaces, _) = dict.tokenize("   ").unwrap();
+        assert_eq!(only_spaces.len(), 1);
+        assert_eq!(only_spaces[0].range, 0..3);
+        
+        let paths = dict.tokenize_nbest(text, 2).unwrap();
+        assert_eq!(paths[0].0.iter().map(|token| token.get_text(text)).collect::<String>(), text);
+        
+        dict.set_space_stripping(false);
+        assert!(dict.tokenize(text).unwrap().0.iter().all(|token| token.kind != TokenType::Whitespace));
+    }
+    
+    #[test]
+    fn test_lru_matrix_cache()
+    {
+        let mut dict = crate::compiler::tests::test_dict();
+        let texts = ["これを持っていけ", "飛行機 abc", "これを"];
+        let expected : Vec<_> = texts.iter().map(|text| dict.tokenize(text).unwrap().1).collect();
+        assert_eq!(dict.lru_matrix_cache_stats(), None);
+        
+        dict.prepare_lru_matrix_cache(1 << 16);
+        for _ in 0..2
+        {
+            assert_eq!(texts.iter().map(|text| dict.tokenize(text).unwrap().1).collect::<Vec<_>>(), expected);
+        }
+        let (hits, misses) = dict.lru_matrix_cache_stats().unwrap();
+        assert!(hits > 0 && misses > 0);
+        
+        dict.prepare_full_matrix_cache();
+        assert_eq!(dict.lru_matrix_cache_stats(), None);
+        dict.prepare_lru_matrix_cache(1 << 16);
+        assert_eq!(dict.lru_matrix_cache_stats(), None);
+    }
+    
+    #[test]
+    fn test_marginals()
+    {
+        let mut dict = crate::compiler::tests::test_dict();
+        let text = "これを持っていけ 飛行機";
+        assert_eq!(dict.tokenize(text).unwrap().0[0].probability, None);
+        
+        assert_eq!(dict.set_marginal_theta(Some(0.01)), None);
+        let (tokens, cost) = dict.tokenize(text).unwrap();
+        assert_eq!(cost, dict.build_lattice(text).best_cost().unwrap());
+        for token in &tokens
+        {
+            let probability = token.probability.unwrap();
+            assert!(probability > 0.0 && probability <= 1.0 + 1e-9);
+        }
+        
+        // every character is covered by exactly one token of each path
+        let lattice = dict.build_lattice(text);
+        for (position, _) in text.char_indices().filter(|(_, c)| *c != ' ')
+        {
+            let covering : f64 = lattice.nodes().iter().filter(|node| node.range.contains(&position)).map(|node| node.probability.unwrap()).sum();
+            assert!((covering - 1.0).abs() < 1e-9);
+        }
+        for (token, &index) in tokens.iter().zip(lattice.best_path())
+        {
+            assert_eq!(token.probability, lattice.nodes()[index].probability);
+        }
+        
+        let mut constraints = Constraints::new();
+        constraints.require_boundary(15);
+        assert!(dict.tokenize_constrained(text, &constraints).unwrap().0.iter().all(|token| token.probability.is_some()));
+        
+        dict.set_marginal_theta(None);
+        assert!(dict.build_lattice(text).nodes().iter().all(|node| node.probability.is_none()));
+    }
+    
+    #[test]
+    fn test_cost_breakdown()
+    {
+        let mut dict = crate::compiler::tests::test_dict();
+        dict.set_whitespace_tokens(true);
+        let text = "これを持っていけ ";
+        let (tokens, cost) = dict.tokenize(text).unwrap();
+        assert_eq!(tokens[0].left_context(), 1);
+        assert_eq!(tokens[0].right_context(), 1);
+        assert_eq!(tokens[0].pos(), 0);
+        
+        let mut total = 0;
+        for (i, token) in tokens.iter().enumerate()
+        {
+            let breakdown = token.cost_breakdown();
+            assert_eq!(breakdown.word, token.cost);
+            assert_eq!(breakdown.word + breakdown.connection, token.real_cost);
+            assert_eq!(breakdown.end_connection.is_some(), i == tokens.len() - 2);
+            total += token.real_cost + breakdown.end_connection.unwrap_or(0);
+        }
+        assert_eq!(total, cost);
+        let last = &tokens[tokens.len() - 2];
+        assert_eq!(last.cost_breakdown().end_connection, Some(dict.access_matrix(last.right_context(), 0) as i64));
+        assert_eq!(tokens[0].cost_breakdown().connection, dict.access_matrix(0, tokens[0].left_context()) as i64);
+    }
+    
+    #[test]
+    fn test_tokenize_nbest()
+    {
+        use crate::compiler::tests::*;
+        let lexicon = "\
+こ,1,1,300,名詞,*,*,*,*,*,コ,子,こ
+れを,2,2,300,助詞,*,*,*,*,*,レヲ,れを,れを
+これを,2,2,400,代名詞,*,*,*,*,*,コレヲ,此れを,これを
+";
+        let dict = CompiledDictionary::compile(&[LEXICON, lexicon], MATRIX_DEF, CHAR_DEF, UNK_DEF).unwrap().load().unwrap();
+        let text = "これを";
+        let (best, best_cost) = dict.tokenize(text).unwrap();
+        
+        let paths = dict.tokenize_nbest(text, 10).unwrap();
+        assert!(paths.len() >= 3);
+        assert_eq!(paths[0].1, best_cost);
+        assert_eq!(paths[0].0.iter().map(|token| token.range.clone()).collect::<Vec<_>>(), best.iter().map(|token| token.range.clone()).collect::<Vec<_>>());
+        assert!(paths.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        let splits : Vec<Vec<(Range<usize>, u32)>> = paths.iter().map(|(tokens, _)| tokens.iter().map(|token| (token.range.clone(), token.original_id)).collect()).collect();
+        for (i, split) in splits.iter().enumerate()
+        {
+            assert!(splits[i + 1..].iter().all(|other| other != split));
+        }
+        for split in &[&[(0, 6), (6, 9)][..], &[(0, 9)][..], &[(0, 3), (3, 9)][..]]
+        {
+            assert!(splits.iter().any(|path| path.iter().map(|(range, _)| (range.start, range.end)).collect::<Vec<_>>() == *split));
+        }
+        for (tokens, cost) in &paths
+        {
+            assert_eq!(tokens.iter().map(|token| token.real_cost + token.cost_breakdown().end_connection.unwrap_or(0)).sum::<i64>(), *cost);
+        }
+        
+        assert_eq!(dict.tokenize_nbest(text, 2).unwrap().len(), 2);
+        assert!(dict.tokenize_nbest(text, 0).unwrap().is_empty());
+        assert!(dict.tokenize_nbest("", 3).is_err());
+    }
+    
+    #[test]
+    fn test_tokenize_constrained()
+    {
+        let dict = crate::compiler::tests::test_dict();
+        let text = "これを持っていけ";
+        let split = |constraints : &Constraints| dict.tokenize_constrained(text, constraints).map(|(tokens, _)| tokenstream_to_string(text, &tokens, "|"));
+        
+        let (_, cost) = dict.tokenize(text).unwrap();
+        assert_eq!(dict.tokenize_constrained(text, &Constraints::new()).unwrap().1, cost);
+        assert_eq!(split(&Constraints::new()).unwrap(), "これ|を|持っ|て|いけ");
+        
+        // required boundary
+        let mut constraints = Constraints::new();
+        constraints.require_boundary(21);
+        assert_eq!(split(&constraints).unwrap(), "これ|を|持っ|て|い|け");
+        assert!(dict.tokenize_constrained(text, &constraints).unwrap().1 > cost);
+        
+        // forbidden boundary
+        let mut constraints = Constraints::new();
+        constraints.forbid_boundary(9);
+        let (tokens, _) = dict.tokenize_constrained(text, &constraints).unwrap();
+        assert!(tokens.iter().all(|token| token.range.start != 9 && token.range.end != 9));
+        assert_eq!(tokens.first().unwrap().range.start, 0);
+        assert_eq!(tokens.last().unwrap().range.end, text.len());
+        assert!(tokens.windows(2).all(|pair| pair[0].range.end == pair[1].range.start));
+        
+        // forced span without a dictionary entry
+        let mut constraints = Constraints::new();
+        constraints.require_token(0..9, None);
+        let (tokens, _) = dict.tokenize_constrained(text, &constraints).unwrap();
+        assert_eq!(tokenstream_to_string(text, &tokens, "|"), "これを|持っ|て|いけ");
+        assert_eq!(tokens[0].kind, TokenType::UNK);
+        assert!(tokens[1..].iter().all(|token| token.kind == TokenType::Normal));
+        
+        // feature prefix picking the more expensive of two entries
+        let (tokens, _) = dict.tokenize("飛行機").unwrap();
+        assert_eq!(tokens[1].get_feature(&dict), "名詞,普通名詞,一般,*,*,*,キ,機,機");
+        let mut constraints = Constraints::new();
+        constraints.require_token(6..9, Some("名詞,普通名詞,一般,*,*,*,ハタ"));
+        let (tokens, _) = dict.tokenize_constrained("飛行機", &constraints).unwrap();
+        assert_eq!(tokens[1].get_feature(&dict), "名詞,普通名詞,一般,*,*,*,ハタ,機,機");
+        assert_eq!(tokens[1].kind, TokenType::Normal);
+        
+        // unsatisfiable constraints
+        let mut constraints = Constraints::new();
+        constraints.require_boundary(3);
+        constraints.require_token(0..6, None);
+        assert!(split(&constraints).is_err());
+        let mut constraints = Constraints::new();
+        constraints.require_boundary(6);
+        constraints.forbid_boundary(6);
+        assert!(split(&constraints).is_err());
+        
+        // positions past the end of the text
+        let mut constraints = Constraints::new();
+        constraints.require_boundary(text.len());
+        assert!(split(&constraints).is_ok());
+        let mut constraints = Constraints::new();
+        constraints.require_boundary(100);
+        assert!(split(&constraints).is_err());
+        let mut constraints = Constraints::new();
+        constraints.forbid_boundary(100);
+        assert!(split(&constraints).is_ok());
+        let mut constraints = Constraints::new();
+        constraints.require_token(0..100, None);
+        assert!(split(&constraints).is_err());
+    }
+    
+    #[test]
+    fn test_load_text_unknowns()
+    {
+        use crate::compiler::tests::*;
+        let compiled = compiled_test_dict();
+        let dict = Dict::load(
+            Blob::new(compiled.sys_dic),
+            Blob::new(UNK_DEF),
+            Blob::new(compiled.matrix),
+            Blob::new(CHAR_DEF)
+        ).unwrap();
+        
+        let text = "噛噛 abc";
+        let (tokens, _) = dict.tokenize(text).unwrap();
+        let surfaces : Vec<&str> = tokens.iter().map(|token| token.get_text(text)).collect();
+        assert_eq!(surfaces, vec!("噛噛", "abc"));
+        assert_eq!(tokens[0].get_feature(&dict), "名詞,普通名詞,一般,*,*,*");
+        
+        let compiled = compiled_test_dict();
+        assert!(Dict::load(Blob::new(compiled.sys_dic), Blob::new("HIRAGANA,1,1,100,名詞"), Blob::new(compiled.matrix), Blob::new(CHAR_DEF)).is_err());
+    }
+    
+    #[test]
+    fn test_load_text_matrix()
+    {
+        use crate::compiler::tests::*;
+        let text = "これを持っていけ";
+        let expected = test_dict().tokenize(text).unwrap().1;
+        
+        let compiled = compiled_test_dict();
+        let dict = Dict::load(Blob::new(compiled.sys_dic), Blob::new(UNK_DEF), Blob::new(MATRIX_DEF), Blob::new(CHAR_DEF)).unwrap();
+        assert_eq!(dict.tokenize(text).unwrap().1, expected);
+        
+        let compiled = compiled_test_dict();
+        assert!(Dict::load(Blob::new(compiled.sys_dic), Blob::new(UNK_DEF), Blob::new("4 5\n0 0 0\n"), Blob::new(CHAR_DEF)).is_err());
+        let compiled = compiled_test_dict();
+        assert!(Dict::load(Blob::new(compiled.sys_dic), Blob::new(UNK_DEF), Blob::new("4 4\n0 4 0\n"), Blob::new(CHAR_DEF)).is_err());
+    }
 }
-
@@ -0,0 +1,203 @@
+use std::mem::size_of;
+use std::sync::Mutex;
+
+use crate::HashMap;
+
+use super::Blob;
+
+// number of costs in a block; blocks are runs of the matrix in file order, so they're pieces of rows
+const BLOCK_LEN : usize = 64;
+const BLOCK_BYTES : usize = BLOCK_LEN * 2;
+// what a cached block takes up: its slot, plus roughly its entry in the index (key, value and the hash table's control byte)
+const SLOT_BYTES : usize = size_of::<Slot>() + 2 * size_of::<usize>() + 1;
+// blocks are spread over several independently locked shards so that threads don't all wait on one lock
+const MAX_SHARDS : usize = 16;
+const NO_SLOT : usize = !0;
+
+struct Slot {
+    block : usize,
+    prev : usize,
+    next : usize,
+    costs : [i16; BLOCK_LEN],
+}
+
+// the blocks of one shard, in a list through the slots from most to least recently used
+struct Shard {
+    slots : Vec<Slot>,
+    index : HashMap<usize, usize>,
+    capacity : usize,
+    head : usize,
+    tail : usize,
+    // kept per shard so that counting doesn't make every thread write to the same memory
+    hits : u64,
+    misses : u64,
+}
+
+impl Shard {
+    fn new(capacity : usize) -> Shard
+    {
+        Shard {
+            slots : Vec::new(),
+            index : HashMap::new(),
+            capacity,
+            head : NO_SLOT,
+            tail : NO_SLOT,
+            hits : 0,
+            misses : 0,
+        }
+    }
+    fn unlink(&mut self, slot : usize)
+    {
+        let (prev, next) = (self.slots[slot].prev, self.slots[slot].next);
+        if prev == NO_SLOT { self.head = next; } else { self.slots[prev].next = next; }
+        if next == NO_SLOT { self.tail = prev; } else { self.slots[next].prev = prev; }
+    }
+    fn push_front(&mut self, slot : usize)
+    {
+        self.slots[slot].prev = NO_SLOT;
+        self.slots[slot].next = self.head;
+        if self.head == NO_SLOT
+        {
+            self.tail = slot;
+        }
+        else
+        {
+            self.slots[self.head].prev = slot;
+        }
+        self.head = slot;
+    }
+    fn get(&mut self, block : usize, offset : usize) -> Option<i16>
+    {
+        let slot = match self.index.get(&block)
+        {
+            Some(&slot) => slot,
+            None =>
+            {
+                self.misses += 1;
+                return None;
+            }
+        };
+        self.hits += 1;
+        if slot != self.head
+        {
+            self.unlink(slot);
+            self.push_front(slot);
+        }
+        Some(self.slots[slot].costs[offset])
+    }
+    fn insert(&mut self, block : usize, costs : [i16; BLOCK_LEN])
+    {
+        // another thread might have loaded the same block in the meantime
+        if self.index.contains_key(&block)
+        {
+            return;
+        }
+        let slot = if self.slots.len() < self.capacity
+        {
+            self.slots.push(Slot { block, prev : NO_SLOT, next : NO_SLOT, costs });
+            self.slots.len() - 1
+        }
+        else
+        {
+            let slot = self.tail;
+            self.unlink(slot);
+            self.index.remove(&self.slots[slot].block);
+            self.slots[slot].block = block;
+            self.slots[slot].costs = costs;
+            slot
+        };
+        self.index.insert(block, slot);
+        self.push_front(slot);
+    }
+}
+
+/// Cache of connection matrix blocks that get read from the blob the first time they're used, evicting the least recently used ones once the memory budget is full.
+pub (crate) struct MatrixCache {
+    shards : Vec<Mutex<Shard>>,
+}
+
+impl MatrixCache {
+    /// Returns None if the budget can't hold a single block. The budget covers the bookkeeping for each block as well as its costs.
+    pub (crate) fn new(memory_budget : usize) -> Option<MatrixCache>
+    {
+        let blocks = memory_budget / SLOT_BYTES;
+        if blocks == 0
+        {
+            return None;
+        }
+        let shard_count = std::cmp::min(MAX_SHARDS, blocks);
+        let shards = (0..shard_count).map(|_| Mutex::new(Shard::new(blocks / shard_count))).collect();
+        Some(MatrixCache { shards })
+    }
+    /// Looks up the cost at `location` (counted in costs, not bytes) of the matrix stored in `blob`.
+    pub (crate) fn get(&self, blob : &Blob, location : usize) -> i16
+    {
+        let block = location / BLOCK_LEN;
+        let offset = location % BLOCK_LEN;
+        let shard = &self.shards[block % self.shards.len()];
+        if let Some(cost) = shard.lock().unwrap().get(block, offset)
+        {
+            return cost;
+        }
+
+        // the 4 is for the two u16s at the beginning that specify the shape of the matrix; the last block can be cut short
+        let start = 4 + block * BLOCK_BYTES;
+        let end = std::cmp::min(start + BLOCK_BYTES, blob.len());
+        let mut costs = [0i16; BLOCK_LEN];
+        for (cost, bytes) in costs.iter_mut().zip(blob[start..end].chunks_exact(2))
+        {
+            *cost = i16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        shard.lock().unwrap().insert(block, costs);
+        costs[offset]
+    }
+    /// Returns how many lookups were and weren't already in the cache.
+    pub (crate) fn stats(&self) -> (u64, u64)
+    {
+        self.shards.iter().fold((0, 0), |(hits, misses), shard| {
+            let shard = shard.lock().unwrap();
+            (hits + shard.hits, misses + shard.misses)
+        })
+    }
+    #[cfg(test)]
+    fn memory_usage(&self) -> usize
+    {
+        self.shards.iter().map(|shard| shard.lock().unwrap().slots.len() * SLOT_BYTES).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matrix_cache()
+    {
+        // a 3x300 matrix where each cost is its own location
+        let mut data = vec!(3u8, 0, 44, 1);
+        for location in 0..900i16
+        {
+            data.extend_from_slice(&location.to_le_bytes());
+        }
+        let blob = Blob::new(data);
+
+        assert!(MatrixCache::new(SLOT_BYTES - 1).is_none());
+
+        let cache = MatrixCache::new(SLOT_BYTES * 4).unwrap();
+        for location in (0..900).chain((0..900).rev()).chain(0..900)
+        {
+            assert_eq!(cache.get(&blob, location), location as i16);
+            assert!(cache.memory_usage() <= SLOT_BYTES * 4);
+        }
+        let (hits, misses) = cache.stats();
+        assert_eq!(hits + misses, 2700);
+
+        // recently used blocks stay, the least recently used one goes
+        let cache = MatrixCache::new(SLOT_BYTES).unwrap();
+        cache.get(&blob, 0);
+        cache.get(&blob, 1);
+        cache.get(&blob, BLOCK_LEN);
+        cache.get(&blob, 2);
+        assert_eq!(cache.stats(), (1, 3));
+    }
+}
@@ -1,3 +1,5 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::ops::Range;
 
 pub struct Cache
@@ -5,7 +7,13 @@ pub struct Cache
     rank_to_range : Vec<Range<u32>>,
     cost_for_node : Vec<Cost>,
     source_node : Vec<u32>,
-    path : Vec<u32>
+    path : Vec<u32>,
+    next_rank_to_range : Vec<Range<u32>>,
+    nodes_by_next_rank : Vec<u32>,
+    queue : BinaryHeap<Reverse<(Cost, u32)>>,
+    partial_paths : Vec<(u32, u32, Cost)>,
+    beta : Vec<f64>,
+    marginals : Vec<f64>
 }
 
 impl Cache
@@ -17,35 +25,69 @@ impl Cache
             rank_to_range : Vec::new(),
             cost_for_node : Vec::new(),
             source_node : Vec::new(),
-            path : Vec::new()
+            path : Vec::new(),
+            next_rank_to_range : Vec::new(),
+            nodes_by_next_rank : Vec::new(),
+            queue : BinaryHeap::new(),
+            partial_paths : Vec::new(),
+            beta : Vec::new(),
+            marginals : Vec::new()
         }
     }
 
+    /// The lowest cost of reaching the given node during the last search, or `None` if it can't be reached.
+    pub fn cost_for_node(&self, index : usize) -> Option<Cost>
+    {
+        match self.cost_for_node.get(index)
+        {
+            Some(&cost) if cost != COST_MAX => Some(cost),
+            _ => None
+        }
+    }
+
+    /// The probability of the given node being on the path, as computed by the last call to `marginals`, or `None` if that wasn't the last search.
+    pub fn marginal(&self, index : usize) -> Option<f64>
+    {
+        self.marginals.get(index).copied()
+    }
+
     fn clear(&mut self)
     {
         self.rank_to_range.clear();
         self.cost_for_node.clear();
         self.source_node.clear();
         self.path.clear();
+        self.next_rank_to_range.clear();
+        self.nodes_by_next_rank.clear();
+        self.queue.clear();
+        self.partial_paths.clear();
+        self.beta.clear();
+        self.marginals.clear();
     }
 }
 
 pub type Cost = i64;
 const COST_MAX: Cost = std::i64::MAX;
 
-pub fn shortest_path(
+struct ForwardPass
+{
+    min_rank : u32,
+    end_rank : u32,
+    lowest_cost_index : u32,
+    lowest_cost : Cost
+}
+
+// Fills in the lowest cost of reaching every node from the start of the graph
+// and the node each of those costs came from.
+fn forward_pass(
     cache: &mut Cache,
     node_count: usize,
-    get_rank: impl Fn(usize) -> u32,
-    get_next_rank: impl Fn(usize) -> u32,
-    get_cost: impl Fn(usize, usize) -> Cost,
-    get_cost_for_start_node: impl Fn(usize) -> Cost,
-    get_cost_for_end_node: impl Fn(usize) -> Cost
-) -> (&[u32], Cost) {
-    if node_count == 0 {
-        return (&[], 0);
-    }
-
+    get_rank: &impl Fn(usize) -> u32,
+    get_next_rank: &impl Fn(usize) -> u32,
+    get_cost: &impl Fn(usize, usize) -> Cost,
+    get_cost_for_start_node: &impl Fn(usize) -> Cost,
+    get_cost_for_end_node: &impl Fn(usize) -> Cost
+) -> ForwardPass {
     cache.clear();
 
     debug_assert!((0..node_count).zip(1..node_count).all(|(index, next_index)| get_rank(next_index) >= get_rank(index)));
@@ -147,8 +189,47 @@ pub fn shortest_path(
         starting_index = range.end;
     }
 
+    ForwardPass
+    {
+        min_rank,
+        end_rank,
+        lowest_cost_index,
+        lowest_cost
+    }
+}
+
+pub fn shortest_path(
+    cache: &mut Cache,
+    node_count: usize,
+    get_rank: impl Fn(usize) -> u32,
+    get_next_rank: impl Fn(usize) -> u32,
+    get_cost: impl Fn(usize, usize) -> Cost,
+    get_cost_for_start_node: impl Fn(usize) -> Cost,
+    get_cost_for_end_node: impl Fn(usize) -> Cost
+) -> (&[u32], Cost) {
+    if node_count == 0 {
+        return (&[], 0);
+    }
+
+    let pass = forward_pass(
+        cache,
+        node_count,
+        &get_rank,
+        &get_next_rank,
+        &get_cost,
+        &get_cost_for_start_node,
+        &get_cost_for_end_node
+    );
+
+    if pass.lowest_cost == COST_MAX {
+        // None of the nodes at the end of the graph can be reached from the start.
+        return (&[], 0);
+    }
+
+    let cost_for_node = &cache.cost_for_node;
+    let source_node = &cache.source_node;
     let path = &mut cache.path;
-    let mut index = lowest_cost_index;
+    let mut index = pass.lowest_cost_index;
     let total_cost = cost_for_node[index as usize] + get_cost_for_end_node(index as usize);
     loop
     {
@@ -164,6 +245,237 @@ pub fn shortest_path(
     (&cache.path, total_cost)
 }
 
+// log(exp(a) + exp(b)) without overflowing
+fn log_add_exp(a : f64, b : f64) -> f64
+{
+    if a == f64::NEG_INFINITY
+    {
+        return b;
+    }
+    if b == f64::NEG_INFINITY
+    {
+        return a;
+    }
+    let (high, low) = if a > b { (a, b) } else { (b, a) };
+    high + (low - high).exp().ln_1p()
+}
+
+/// Computes the probability of each node being on the path when every path through the graph is
+/// weighted by `exp(-theta * cost)`, with the forward-backward algorithm. The probabilities can then
+/// be read with `Cache::marginal`. Returns false if no path goes through the whole graph.
+///
+/// The forward pass of `shortest_path` is run first, so `Cache::cost_for_node` is filled in too.
+/// Everything is done in log space, so large costs and values of `theta` don't underflow.
+#[allow(clippy::too_many_arguments)]
+pub fn marginals(
+    cache: &mut Cache,
+    node_count: usize,
+    theta: f64,
+    get_rank: impl Fn(usize) -> u32,
+    get_next_rank: impl Fn(usize) -> u32,
+    get_cost: impl Fn(usize, usize) -> Cost,
+    get_cost_for_start_node: impl Fn(usize) -> Cost,
+    get_cost_for_end_node: impl Fn(usize) -> Cost
+) -> bool {
+    if node_count == 0 {
+        return false;
+    }
+
+    let pass = forward_pass(
+        cache,
+        node_count,
+        &get_rank,
+        &get_next_rank,
+        &get_cost,
+        &get_cost_for_start_node,
+        &get_cost_for_end_node
+    );
+    if pass.lowest_cost == COST_MAX {
+        return false;
+    }
+
+    let rank_to_range = &cache.rank_to_range;
+    let max_rank = rank_to_range.len() as u32 - 1;
+    let weight = |cost : Cost| -theta * cost as f64;
+
+    // Nodes only lead into nodes of a higher rank, and they're sorted by rank, so the log-sum of the
+    // weights of every path from the start to a node (alpha) can be built going forwards...
+    let alpha = &mut cache.marginals;
+    alpha.resize(node_count, f64::NEG_INFINITY);
+    for index in rank_to_range[pass.min_rank as usize].clone()
+    {
+        alpha[index as usize] = weight(get_cost_for_start_node(index as usize));
+    }
+    let mut log_total = f64::NEG_INFINITY;
+    for index in 0..node_count
+    {
+        if alpha[index] == f64::NEG_INFINITY
+        {
+            continue;
+        }
+        let next_rank = get_next_rank(index);
+        if next_rank > max_rank
+        {
+            if next_rank == pass.end_rank
+            {
+                log_total = log_add_exp(log_total, alpha[index] + weight(get_cost_for_end_node(index)));
+            }
+            continue;
+        }
+        for next_index in rank_to_range[next_rank as usize].clone()
+        {
+            let next_index = next_index as usize;
+            alpha[next_index] = log_add_exp(alpha[next_index], alpha[index] + weight(get_cost(index, next_index)));
+        }
+    }
+
+    // ...and the log-sum of every path from a node to the end (beta) going backwards.
+    let beta = &mut cache.beta;
+    beta.resize(node_count, f64::NEG_INFINITY);
+    for index in (0..node_count).rev()
+    {
+        let next_rank = get_next_rank(index);
+        if next_rank > max_rank
+        {
+            if next_rank == pass.end_rank
+            {
+                beta[index] = weight(get_cost_for_end_node(index));
+            }
+            continue;
+        }
+        let mut sum = f64::NEG_INFINITY;
+        for next_index in rank_to_range[next_rank as usize].clone()
+        {
+            let next_index = next_index as usize;
+            if beta[next_index] != f64::NEG_INFINITY
+            {
+                sum = log_add_exp(sum, weight(get_cost(index, next_index)) + beta[next_index]);
+            }
+        }
+        beta[index] = sum;
+    }
+
+    for (alpha, beta) in alpha.iter_mut().zip(beta.iter())
+    {
+        *alpha = (*alpha + *beta - log_total).exp();
+    }
+    true
+}
+
+/// Finds up to `count` lowest-cost paths and hands each one to `on_path`, in ascending order of cost.
+///
+/// The forward pass is the same as in `shortest_path`. The paths are then built backwards from the
+/// end of the graph, using the lowest cost of reaching each node as the estimate for the rest of the
+/// path. That estimate is exact, so complete paths come off the queue already sorted.
+#[allow(clippy::too_many_arguments)]
+pub fn shortest_paths(
+    cache: &mut Cache,
+    node_count: usize,
+    count: usize,
+    get_rank: impl Fn(usize) -> u32,
+    get_next_rank: impl Fn(usize) -> u32,
+    get_cost: impl Fn(usize, usize) -> Cost,
+    get_cost_for_start_node: impl Fn(usize) -> Cost,
+    get_cost_for_end_node: impl Fn(usize) -> Cost,
+    mut on_path: impl FnMut(&[u32], Cost)
+) {
+    if node_count == 0 || count == 0 {
+        return;
+    }
+
+    let pass = forward_pass(
+        cache,
+        node_count,
+        &get_rank,
+        &get_next_rank,
+        &get_cost,
+        &get_cost_for_start_node,
+        &get_cost_for_end_node
+    );
+
+    // Group the nodes by the rank they lead into, so that we can find the predecessors of a node.
+    let next_rank_to_range = &mut cache.next_rank_to_range;
+    next_rank_to_range.resize((pass.end_rank + 1) as usize, 0..0);
+    for index in 0..node_count
+    {
+        next_rank_to_range[get_next_rank(index) as usize].end += 1;
+    }
+    let mut offset = 0;
+    for range in next_rank_to_range.iter_mut()
+    {
+        let length = range.end;
+        *range = offset..offset;
+        offset += length;
+    }
+
+    let nodes_by_next_rank = &mut cache.nodes_by_next_rank;
+    nodes_by_next_rank.resize(node_count, 0);
+    for index in 0..node_count
+    {
+        let range = &mut next_rank_to_range[get_next_rank(index) as usize];
+        nodes_by_next_rank[range.end as usize] = index as u32;
+        range.end += 1;
+    }
+
+    let cost_for_node = &cache.cost_for_node;
+    let queue = &mut cache.queue;
+    // (node, index of the partial path following this node, cost from this node to the end)
+    let partial_paths = &mut cache.partial_paths;
+    let path = &mut cache.path;
+
+    let end_range = next_rank_to_range[pass.end_rank as usize].clone();
+    for &index in &nodes_by_next_rank[end_range.start as usize..end_range.end as usize]
+    {
+        let reach_cost = cost_for_node[index as usize];
+        if reach_cost == COST_MAX
+        {
+            continue;
+        }
+        let remaining_cost = get_cost_for_end_node(index as usize);
+        queue.push(Reverse((reach_cost + remaining_cost, partial_paths.len() as u32)));
+        partial_paths.push((index, u32::MAX, remaining_cost));
+    }
+
+    let mut found = 0;
+    while let Some(Reverse((total_cost, partial_index))) = queue.pop()
+    {
+        let (index, _, remaining_cost) = partial_paths[partial_index as usize];
+        let rank = get_rank(index as usize);
+        if rank == pass.min_rank
+        {
+            path.clear();
+            let mut partial_index = partial_index;
+            while partial_index != u32::MAX
+            {
+                let (index, next, _) = partial_paths[partial_index as usize];
+                path.push(index);
+                partial_index = next;
+            }
+
+            on_path(path, total_cost);
+            found += 1;
+            if found == count
+            {
+                break;
+            }
+            continue;
+        }
+
+        let previous_range = next_rank_to_range[rank as usize].clone();
+        for &previous_index in &nodes_by_next_rank[previous_range.start as usize..previous_range.end as usize]
+        {
+            let reach_cost = cost_for_node[previous_index as usize];
+            if reach_cost == COST_MAX
+            {
+                continue;
+            }
+            let remaining_cost = get_cost(previous_index as usize, index as usize) + remaining_cost;
+            queue.push(Reverse((reach_cost + remaining_cost, partial_paths.len() as u32)));
+            partial_paths.push((previous_index, partial_index, remaining_cost));
+        }
+    }
+}
+
 #[test]
 fn test_shortest_path()
 {
@@ -405,4 +717,159 @@ fn test_shortest_path()
     );
     assert_eq!(path, &[1]);
     assert_eq!(total_cost, 1);
+
+    let (path, total_cost) = shortest_path(
+        &mut cache,
+        3,
+        |index| match index {
+            0 => 0,
+            1 => 2,
+            2 => 2,
+            _ => unreachable!()
+        },
+        |index| match index {
+            0 => 1,
+            1 => 3,
+            2 => 4,
+            _ => unreachable!()
+        },
+        |_, _| unreachable!(),
+        |_| 0,
+        |_| 0
+    );
+    assert_eq!(path, &[]);
+    assert_eq!(total_cost, 0);
+}
+
+#[test]
+fn test_shortest_paths()
+{
+    fn collect(
+        cache: &mut Cache,
+        node_count: usize,
+        count: usize,
+        get_rank: impl Fn(usize) -> u32,
+        get_next_rank: impl Fn(usize) -> u32,
+        get_cost: impl Fn(usize, usize) -> Cost
+    ) -> Vec<(Vec<u32>, Cost)> {
+        let mut paths = Vec::new();
+        shortest_paths(cache, node_count, count, get_rank, get_next_rank, get_cost, |_| 0, |_| 0, |path, cost| paths.push((path.to_vec(), cost)));
+        paths
+    }
+
+    let mut cache = Cache::new();
+    let paths = collect(
+        &mut cache,
+        0,
+        10,
+        |_| unreachable!(),
+        |_| unreachable!(),
+        |_, _| unreachable!()
+    );
+    assert_eq!(paths, vec![]);
+
+    let rank = |index| match index {
+        0 | 1 => 0,
+        2 | 3 => 1,
+            4 => 2,
+        _ => unreachable!()
+    };
+    let next_rank = |index| match index {
+        0 | 1 => 1,
+        2 | 3 => 2,
+            4 => 3,
+        _ => unreachable!()
+    };
+    let cost = |a, b| match (a, b) {
+        (0, 2) => 100,
+        (0, 3) => 0,
+        (1, 2) => 100,
+        (1, 3) => 100,
+        (2, 4) => 0,
+        (3, 4) => 10000,
+        _ => unreachable!()
+    };
+
+    let paths = collect(&mut cache, 5, 10, rank, next_rank, cost);
+    assert_eq!(paths, vec![
+        (vec![0, 2, 4], 100),
+        (vec![1, 2, 4], 100),
+        (vec![0, 3, 4], 10000),
+        (vec![1, 3, 4], 10100)
+    ]);
+
+    let paths = collect(&mut cache, 5, 3, rank, next_rank, cost);
+    assert_eq!(paths.len(), 3);
+    assert_eq!(paths[2], (vec![0, 3, 4], 10000));
+
+    let paths = collect(&mut cache, 5, 0, rank, next_rank, cost);
+    assert_eq!(paths, vec![]);
+
+    // Node 1 jumps straight to the end, nodes 0 -> 2 -> 3 -> 4 go the long way.
+    let paths = collect(
+        &mut cache,
+        5,
+        10,
+        |index| match index {
+            0 => 0,
+            1 => 0,
+            2 => 1,
+            3 => 2,
+            4 => 3,
+            _ => unreachable!()
+        },
+        |index| match index {
+            0 => 1,
+            1 => 4,
+            2 => 2,
+            3 => 3,
+            4 => 4,
+            _ => unreachable!()
+        },
+        |a, b| match (a, b) {
+            (0, 2) => 1,
+            (2, 3) => 1,
+            (3, 4) => 1,
+            _ => unreachable!()
+        }
+    );
+    assert_eq!(paths, vec![
+        (vec![1], 0),
+        (vec![0, 2, 3, 4], 3)
+    ]);
+}
+
+#[test]
+fn test_marginals()
+{
+    let mut cache = Cache::new();
+    assert!(!marginals(&mut cache, 0, 1.0, |_| unreachable!(), |_| unreachable!(), |_, _| unreachable!(), |_| unreachable!(), |_| unreachable!()));
+
+    // 0 covers the whole graph on its own, and 1 or 2 can be followed by 3
+    let get_rank = |index| [0, 0, 0, 1][index];
+    let get_next_rank = |index| [2, 1, 1, 2][index];
+    let get_cost = |left, right| match (left, right) {
+        (1, 3) => 1,
+        (2, 3) => 5,
+        _ => unreachable!()
+    };
+    assert!(marginals(&mut cache, 4, 1.0, get_rank, get_next_rank, get_cost, |_| 0, |_| 0));
+    let total = 1.0 + (-1.0f64).exp() + (-5.0f64).exp();
+    let expected = [1.0 / total, (-1.0f64).exp() / total, (-5.0f64).exp() / total, 1.0 - 1.0 / total];
+    for (index, expected) in expected.iter().enumerate()
+    {
+        assert!((cache.marginal(index).unwrap() - expected).abs() < 1e-12);
+    }
+    assert_eq!(cache.cost_for_node(3), Some(1));
+
+    // every path is equally likely with a theta of 0
+    assert!(marginals(&mut cache, 4, 0.0, get_rank, get_next_rank, get_cost, |_| 0, |_| 0));
+    assert!((cache.marginal(0).unwrap() - 1.0 / 3.0).abs() < 1e-12);
+
+    // huge costs don't underflow
+    assert!(marginals(&mut cache, 4, 1.0, get_rank, get_next_rank, |_, _| 100_000, |_| 100_000, |_| 0));
+    assert!((cache.marginal(0).unwrap() - 1.0).abs() < 1e-12);
+
+    shortest_path(&mut cache, 4, get_rank, get_next_rank, get_cost, |_| 0, |_| 0);
+    assert_eq!(cache.marginal(0), None);
 }
@@ -0,0 +1,254 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+
+use crate::HashMap;
+
+use super::Cache;
+use super::Dict;
+
+const HEADER : &str = "# notmecab edge profile: left edge, right edge, count";
+
+/// Counts of how often each connection cost was looked up while tokenizing some text, for picking the edges that [`Dict::prepare_fast_matrix_cache`] should cache.
+///
+/// Fill it with [`Dict::profile_edges`] over a sample of the text you expect to tokenize, then pass it to [`Dict::prepare_fast_matrix_cache_from_profile`]. Profiles can be saved and loaded, so that the profiling only has to be done once per dictionary version.
+///
+/// "Left" and "right" are meant in the same way as in [`Dict::prepare_fast_matrix_cache`]: a left edge is the right context ID of the token on the left of a connection, and a right edge is the left context ID of the token on the right.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq, Eq)]
+pub struct EdgeProfile {
+    counts : HashMap<(u16, u16), u64>,
+}
+
+// Fenwick tree for prefix sums of counts, indexed by rank.
+struct PrefixSums(Vec<u64>);
+
+impl PrefixSums {
+    fn add(&mut self, index : usize, value : u64)
+    {
+        let mut index = index + 1;
+        while index < self.0.len()
+        {
+            self.0[index] += value;
+            index += index & index.wrapping_neg();
+        }
+    }
+    // sum of everything below index
+    fn sum(&self, mut index : usize) -> u64
+    {
+        let mut sum = 0;
+        while index > 0
+        {
+            sum += self.0[index];
+            index -= index & index.wrapping_neg();
+        }
+        sum
+    }
+}
+
+// edge IDs, most used first, along with each ID's rank
+fn rank_edges(totals : HashMap<u16, u64>) -> (Vec<u16>, HashMap<u16, usize>)
+{
+    let mut edges : Vec<(u16, u64)> = totals.into_iter().collect();
+    edges.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    let ranks = edges.iter().enumerate().map(|(rank, (edge, _))| (*edge, rank)).collect();
+    (edges.into_iter().map(|(edge, _)| edge).collect(), ranks)
+}
+
+impl EdgeProfile {
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// Counts one lookup of the connection cost between the given edges.
+    pub fn record(&mut self, left : u16, right : u16)
+    {
+        *self.counts.entry((left, right)).or_insert(0) += 1;
+    }
+
+    /// Adds the counts of another profile to this one.
+    pub fn merge(&mut self, other : &EdgeProfile)
+    {
+        for (&edges, &count) in &other.counts
+        {
+            *self.counts.entry(edges).or_insert(0) += count;
+        }
+    }
+
+    /// Returns how many times the connection cost between the given edges was looked up.
+    pub fn count(&self, left : u16, right : u16) -> u64
+    {
+        self.counts.get(&(left, right)).copied().unwrap_or(0)
+    }
+
+    /// Returns the total number of lookups recorded.
+    pub fn total(&self) -> u64
+    {
+        self.counts.values().sum()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.counts.is_empty()
+    }
+
+    /// Picks sets of left and right edges whose sub-matrix of connection costs takes up at most `memory_budget` bytes (two per cost), covering as many of the recorded lookups as possible.
+    ///
+    /// Edges are taken in order of how often they were used, and the split between left and right edges that covers the most lookups wins. Returns the left and right edges, most used first.
+    pub fn select_edges(&self, memory_budget : usize) -> (Vec<u16>, Vec<u16>)
+    {
+        let cells = memory_budget / 2;
+        let mut left_totals : HashMap<u16, u64> = HashMap::new();
+        let mut right_totals : HashMap<u16, u64> = HashMap::new();
+        for (&(left, right), &count) in &self.counts
+        {
+            *left_totals.entry(left).or_insert(0) += count;
+            *right_totals.entry(right).or_insert(0) += count;
+        }
+        let (lefts, left_ranks) = rank_edges(left_totals);
+        let (rights, right_ranks) = rank_edges(right_totals);
+        if cells == 0 || lefts.is_empty()
+        {
+            return (Vec::new(), Vec::new());
+        }
+
+        // lookups by the rank of their left edge, each with the rank of its right edge
+        let mut by_left_rank : Vec<Vec<(usize, u64)>> = vec!(Vec::new(); lefts.len());
+        for (&(left, right), &count) in &self.counts
+        {
+            by_left_rank[left_ranks[&left]].push((right_ranks[&right], count));
+        }
+
+        // try every number of left edges, with as many right edges as fit alongside them
+        let mut covered = PrefixSums(vec!(0; rights.len() + 1));
+        let mut best = (0, 0, 0);
+        for left_count in 1..=std::cmp::min(lefts.len(), cells)
+        {
+            for &(right_rank, count) in &by_left_rank[left_count - 1]
+            {
+                covered.add(right_rank, count);
+            }
+            let right_count = std::cmp::min(rights.len(), cells / left_count);
+            let coverage = covered.sum(right_count);
+            if coverage > best.2
+            {
+                best = (left_count, right_count, coverage);
+            }
+        }
+        (lefts[..best.0].to_vec(), rights[..best.1].to_vec())
+    }
+
+    /// Writes the profile as text, one line per pair of edges.
+    pub fn write_to<W : Write>(&self, mut writer : W) -> io::Result<()>
+    {
+        let mut counts : Vec<(&(u16, u16), &u64)> = self.counts.iter().collect();
+        counts.sort();
+        writeln!(writer, "{}", HEADER)?;
+        for ((left, right), count) in counts
+        {
+            writeln!(writer, "{} {} {}", left, right, count)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a profile written by [`EdgeProfile::write_to`].
+    pub fn read_from<R : BufRead>(reader : R) -> io::Result<EdgeProfile>
+    {
+        let mut profile = EdgeProfile::new();
+        for (index, line) in reader.lines().enumerate()
+        {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#')
+            {
+                continue;
+            }
+            let error = || io::Error::new(io::ErrorKind::InvalidData, format!("edge profile: line {}: expected a left edge, a right edge and a count", index + 1));
+            let fields : Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3
+            {
+                return Err(error());
+            }
+            let left = fields[0].parse::<u16>().map_err(|_| error())?;
+            let right = fields[1].parse::<u16>().map_err(|_| error())?;
+            let count = fields[2].parse::<u64>().map_err(|_| error())?;
+            *profile.counts.entry((left, right)).or_insert(0) += count;
+        }
+        Ok(profile)
+    }
+}
+
+impl Dict {
+    /// Tokenizes `text` and records every connection cost that was looked up in the process into `profile`.
+    pub fn profile_edges(&self, profile : &mut EdgeProfile, text : &str)
+    {
+        let mut cache = Cache::new();
+        let mut tokens = Vec::new();
+        super::generate_potential_tokens(self, text, &mut tokens);
+
+        let profile = RefCell::new(profile);
+        let record = |left, right| profile.borrow_mut().record(left, right);
+        crate::pathing::shortest_path(
+            &mut cache.pathing_cache,
+            tokens.len(),
+            |index| tokens[index].rank,
+            |index| tokens[index].range.end as u32,
+            |left, right| { record(tokens[left].right_context, tokens[right].left_context); self.cost_between(&tokens[left], &tokens[right]) },
+            |index| { record(0, tokens[index].left_context); self.cost_from_start(&tokens[index]) },
+            |index| { record(tokens[index].right_context, 0); self.cost_to_end(&tokens[index]) }
+        );
+    }
+
+    /// Calls [`Dict::prepare_fast_matrix_cache`] with the edges that [`EdgeProfile::select_edges`] picks for the given memory budget, in bytes.
+    ///
+    /// Edges that don't exist in this dictionary's connection matrix (e.g. from a profile made with a different version of it) are skipped.
+    pub fn prepare_fast_matrix_cache_from_profile(&mut self, profile : &EdgeProfile, memory_budget : usize)
+    {
+        let (mut lefts, mut rights) = profile.select_edges(memory_budget);
+        lefts.retain(|&left| left < self.left_edges);
+        rights.retain(|&right| right < self.right_edges);
+        self.prepare_fast_matrix_cache(lefts, rights);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::tests::test_dict;
+
+    #[test]
+    fn test_edge_profile()
+    {
+        let mut dict = test_dict();
+        let mut profile = EdgeProfile::new();
+        let texts = ["これを持っていけ", "飛行機 abc", "これを"];
+        for text in &texts
+        {
+            dict.profile_edges(&mut profile, text);
+        }
+        assert!(profile.total() > 0);
+        assert!(profile.count(0, 1) > 0);
+
+        let mut saved = Vec::new();
+        profile.write_to(&mut saved).unwrap();
+        assert_eq!(EdgeProfile::read_from(&saved[..]).unwrap(), profile);
+        assert!(EdgeProfile::read_from(&b"1 2\n"[..]).is_err());
+
+        // everything fits
+        let (lefts, rights) = profile.select_edges(1000);
+        assert!(profile.counts.keys().all(|(left, right)| lefts.contains(left) && rights.contains(right)));
+        // room for a single cost
+        let (lefts, rights) = profile.select_edges(2);
+        assert_eq!((lefts.len(), rights.len()), (1, 1));
+        assert!(profile.count(lefts[0], rights[0]) > 0);
+        assert_eq!(profile.select_edges(1), (Vec::new(), Vec::new()));
+
+        let expected : Vec<_> = texts.iter().map(|text| dict.tokenize(text).unwrap().1).collect();
+        dict.prepare_fast_matrix_cache_from_profile(&profile, 8);
+        assert_eq!(texts.iter().map(|text| dict.tokenize(text).unwrap().1).collect::<Vec<_>>(), expected);
+    }
+}
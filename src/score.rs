@@ -0,0 +1,189 @@
+use std::ops::Range;
+
+use super::Constraints;
+use super::Dict;
+use super::LexerToken;
+use super::TokenizeError;
+
+/// A stretch of text between two positions where both a scored segmentation and the best path have a token boundary. See [`SegmentationScore::spans`].
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq, Eq)]
+pub struct SpanScore {
+    /// The range, in bytes, of the text covered by the span.
+    pub range : Range<usize>,
+    /// Indices into [`SegmentationScore::tokens`] of the tokens in this span.
+    pub tokens : Range<usize>,
+    /// Indices into [`SegmentationScore::best_tokens`] of the tokens in this span.
+    pub best_tokens : Range<usize>,
+    /// What the scored segmentation's tokens in this span add to its total cost: word costs, the connection costs into each token, and the connection cost to the end of the string if the span is the last one.
+    pub cost : i64,
+    /// The same for the best path.
+    pub best_cost : i64,
+}
+
+impl SpanScore {
+    /// How much more this span costs in the scored segmentation than in the best path. Can be negative for a single span if the best path makes up for it in another one; only the sum over all spans is never negative.
+    pub fn difference(&self) -> i64
+    {
+        self.cost - self.best_cost
+    }
+}
+
+/// The result of [`Dict::score_segmentation`].
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct SegmentationScore {
+    /// The tokens of the scored segmentation.
+    pub tokens : Vec<LexerToken>,
+    /// The total cost of the scored segmentation, as the pathfinding would have computed it.
+    pub cost : i64,
+    /// The tokens of the best path, as returned by [`Dict::tokenize`].
+    pub best_tokens : Vec<LexerToken>,
+    pub best_cost : i64,
+    /// Both tokenizations split up at every position where both have a token boundary, in order. Spans where the two have the same tokens and costs are included too, so the differences of all spans add up to `cost - best_cost`.
+    pub spans : Vec<SpanScore>,
+}
+
+impl SegmentationScore {
+    /// Returns the spans where the scored segmentation and the best path differ, either in their tokens or in their costs.
+    pub fn differences(&self) -> impl Iterator<Item = &SpanScore>
+    {
+        self.spans.iter().filter(move |span| span.cost != span.best_cost || !same_tokens(&self.tokens[span.tokens.clone()], &self.best_tokens[span.best_tokens.clone()]))
+    }
+}
+
+fn same_tokens(a : &[LexerToken], b : &[LexerToken]) -> bool
+{
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.range == b.range && a.kind == b.kind && a.original_id == b.original_id && a.feature_offset == b.feature_offset)
+}
+
+fn span_cost(tokens : &[LexerToken]) -> i64
+{
+    tokens.iter().map(|token| token.real_cost + token.cost_breakdown().end_connection.unwrap_or(0)).sum()
+}
+
+// Splits both token lists up at the positions where both have a token boundary.
+fn split_into_spans(tokens : &[LexerToken], best_tokens : &[LexerToken]) -> Vec<SpanScore>
+{
+    let mut spans = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < tokens.len() && j < best_tokens.len()
+    {
+        let (first, best_first) = (i, j);
+        let start = std::cmp::min(tokens[i].range.start, best_tokens[j].range.start);
+        let mut end = tokens[i].range.end;
+        let mut best_end = best_tokens[j].range.end;
+        i += 1;
+        j += 1;
+        while end != best_end
+        {
+            if end < best_end && i < tokens.len()
+            {
+                end = tokens[i].range.end;
+                i += 1;
+            }
+            else if best_end < end && j < best_tokens.len()
+            {
+                best_end = best_tokens[j].range.end;
+                j += 1;
+            }
+            else
+            {
+                // the two don't end at the same place, which they always should; put the rest into this span
+                end = tokens[tokens.len() - 1].range.end;
+                best_end = best_tokens[best_tokens.len() - 1].range.end;
+                i = tokens.len();
+                j = best_tokens.len();
+                break;
+            }
+        }
+        spans.push(SpanScore {
+            range : start..std::cmp::max(end, best_end),
+            tokens : first..i,
+            best_tokens : best_first..j,
+            cost : span_cost(&tokens[first..i]),
+            best_cost : span_cost(&best_tokens[best_first..j]),
+        });
+    }
+    spans
+}
+
+impl Dict {
+    /// Scores a segmentation of `text` that comes from somewhere else, such as mecab's output or a linguist's annotation, and compares it against the best path.
+    ///
+    /// Each segment is a byte range that has to come out as a single token, optionally with a prefix that the token's feature string has to start with, which can be used to pick a specific dictionary entry (e.g. by giving its whole feature string). Segments without a fitting dictionary entry are covered by unknown tokens like in [`Dict::tokenize_constrained`], which is what this is built on. Gaps between the segments are tokenized freely.
+    ///
+    /// The cost of the segmentation is computed with the same word and connection costs that the pathfinding uses, so `cost` is never lower than `best_cost`.
+    ///
+    /// Returns an error if the segments overlap, aren't on character boundaries, or can't be turned into tokens at all.
+    pub fn score_segmentation(&self, text : &str, segments : &[(Range<usize>, Option<&str>)]) -> Result<SegmentationScore, TokenizeError>
+    {
+        let mut constraints = Constraints::new();
+        let mut previous_end = 0;
+        let mut sorted : Vec<&(Range<usize>, Option<&str>)> = segments.iter().collect();
+        sorted.sort_by_key(|(range, _)| range.start);
+        for (range, feature_prefix) in sorted
+        {
+            if range.start < previous_end || range.start >= range.end || !text.is_char_boundary(range.start) || !text.is_char_boundary(range.end)
+            {
+                return Err(TokenizeError { _dummy : () });
+            }
+            constraints.require_token(range.clone(), *feature_prefix);
+            previous_end = range.end;
+        }
+
+        let (tokens, cost) = self.tokenize_constrained(text, &constraints)?;
+        let (best_tokens, best_cost) = self.tokenize(text)?;
+        let spans = split_into_spans(&tokens, &best_tokens);
+        Ok(SegmentationScore { tokens, cost, best_tokens, best_cost, spans })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::tests::test_dict;
+    use crate::TokenType;
+
+    #[test]
+    fn test_score_segmentation()
+    {
+        let dict = test_dict();
+        let text = "これを持っていけ";
+        let ranges = [0..6, 6..9, 9..15, 15..18, 18..21, 21..24];
+        let segments : Vec<_> = ranges.iter().map(|range| (range.clone(), None)).collect();
+        let score = dict.score_segmentation(text, &segments).unwrap();
+
+        assert_eq!(score.tokens.iter().map(|token| token.range.clone()).collect::<Vec<_>>(), ranges);
+        assert_eq!(score.best_tokens.iter().map(|token| token.get_text(text)).collect::<Vec<_>>(), vec!("これ", "を", "持っ", "て", "いけ"));
+        assert!(score.cost > score.best_cost);
+        assert_eq!(score.spans.len(), 5);
+        assert_eq!(score.spans.iter().map(|span| span.difference()).sum::<i64>(), score.cost - score.best_cost);
+        assert_eq!(score.spans.iter().map(|span| span.cost).sum::<i64>(), score.cost);
+
+        let differences : Vec<_> = score.differences().collect();
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].range, 18..24);
+        assert_eq!(differences[0].tokens, 4..6);
+        assert_eq!(differences[0].best_tokens, 4..5);
+        assert_eq!(differences[0].difference(), score.cost - score.best_cost);
+
+        // the best path scores the same as itself
+        let best : Vec<_> = score.best_tokens.iter().map(|token| (token.range.clone(), None)).collect();
+        let same = dict.score_segmentation(text, &best).unwrap();
+        assert_eq!(same.cost, same.best_cost);
+        assert_eq!(same.differences().count(), 0);
+
+        // feature prefixes pick entries, and segments without an entry become unknown tokens
+        let text = "飛行機";
+        let score = dict.score_segmentation(text, &[(0..6, None), (6..9, Some("名詞,普通名詞,一般,*,*,*,ハタ"))]).unwrap();
+        assert_eq!(score.tokens[1].get_feature(&dict), "名詞,普通名詞,一般,*,*,*,ハタ,機,機");
+        assert_eq!(score.differences().map(|span| span.range.clone()).collect::<Vec<_>>(), vec!(6..9));
+        let score = dict.score_segmentation(text, &[(0..9, None)]).unwrap();
+        assert_eq!(score.tokens[0].kind, TokenType::UNK);
+        assert_eq!(score.spans.len(), 1);
+
+        assert!(dict.score_segmentation(text, &[(0..6, None), (3..9, None)]).is_err());
+        assert!(dict.score_segmentation(text, &[(0..4, None)]).is_err());
+    }
+}
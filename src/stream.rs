@@ -0,0 +1,246 @@
+use std::io;
+use std::io::BufRead;
+use std::str;
+
+use super::Cache;
+use super::Dict;
+use super::LexerToken;
+
+const DEFAULT_MAX_CHUNK_LENGTH : usize = 0x10000;
+
+/// One line (or piece of a very long line) of a stream, tokenized. Returned by [`TokenStream`].
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct StreamSentence {
+    /// Byte offset of the start of `text` in the stream.
+    pub offset : usize,
+    /// The text that was tokenized, without its line terminator.
+    pub text : String,
+    /// The tokens. Their ranges are relative to the start of the whole stream, not to `text`.
+    pub tokens : Vec<LexerToken>,
+    /// Total cost of the tokens.
+    pub cost : i64,
+}
+
+impl StreamSentence {
+    /// Returns the text to which the given token from this sentence corresponds to.
+    pub fn token_text(&self, token : &LexerToken) -> &str
+    {
+        &self.text[token.range.start - self.offset..token.range.end - self.offset]
+    }
+}
+
+/// Iterator returned by [`Dict::tokenize_stream`].
+///
+/// Yields one [`StreamSentence`] per non-empty line. Lines of nothing but 0x20 spaces are skipped too if space stripping would leave nothing of them to tokenize, unless [`Dict::set_whitespace_tokens`] is enabled. Lines longer than the maximum chunk length are split up, preferably after sentence-ending punctuation, otherwise after whitespace, otherwise wherever the limit falls; tokens never cross those splits. Memory use is bounded by the maximum chunk length no matter how long the lines are.
+///
+/// Errors from the reader are passed through. Invalid UTF-8 gives an error of kind [`io::ErrorKind::InvalidData`], and a line that can't be tokenized gives an error wrapping a [`crate::TokenizeError`]. Iteration stops after an error.
+pub struct TokenStream<'a, R : BufRead> {
+    dict : &'a Dict,
+    reader : R,
+    cache : Cache,
+    // bytes read from the reader but not tokenized yet
+    buffer : Vec<u8>,
+    // offset of the start of the buffer in the stream
+    offset : usize,
+    max_chunk_length : usize,
+    at_end : bool,
+}
+
+impl<'a, R : BufRead> TokenStream<'a, R> {
+    pub (crate) fn new(dict : &'a Dict, reader : R) -> Self
+    {
+        TokenStream {
+            dict,
+            reader,
+            cache : Cache::new(),
+            buffer : Vec::new(),
+            offset : 0,
+            max_chunk_length : DEFAULT_MAX_CHUNK_LENGTH,
+            at_end : false,
+        }
+    }
+
+    /// Sets the length, in bytes, past which lines get split up. Clamped to at least 4 bytes. Returns the previous setting.
+    ///
+    /// Defaults to 64KiB.
+    pub fn set_max_chunk_length(&mut self, length : usize) -> usize
+    {
+        let prev = self.max_chunk_length;
+        self.max_chunk_length = length.max(4);
+        prev
+    }
+
+    // Returns the length of the next chunk, and how many bytes after it to skip over.
+    fn next_chunk(&mut self) -> io::Result<Option<(usize, usize)>>
+    {
+        loop
+        {
+            let limit = self.buffer.len().min(self.max_chunk_length);
+            if let Some(newline) = self.buffer[..limit].iter().position(|&byte| byte == b'\n')
+            {
+                let length = if newline > 0 && self.buffer[newline - 1] == b'\r' { newline - 1 } else { newline };
+                return Ok(Some((length, newline + 1 - length)));
+            }
+            if self.buffer.len() >= self.max_chunk_length
+            {
+                return Ok(Some((self.split_point()?, 0)));
+            }
+            if self.at_end
+            {
+                if self.buffer.is_empty()
+                {
+                    return Ok(None);
+                }
+                return Ok(Some((self.buffer.len(), 0)));
+            }
+
+            let data = self.reader.fill_buf()?;
+            if data.is_empty()
+            {
+                self.at_end = true;
+                continue;
+            }
+            let length = data.len().min(self.max_chunk_length - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..length]);
+            self.reader.consume(length);
+        }
+    }
+
+    // Whether the chunk is all spaces that space stripping removes without putting anything back, which tokenizing fails on.
+    fn is_stripped_away(&self, chunk : &[u8]) -> bool
+    {
+        self.dict.use_space_stripping && !self.dict.use_whitespace_tokens && chunk.iter().all(|&byte| byte == b' ')
+    }
+
+    // Where to split a chunk that doesn't contain a line break.
+    fn split_point(&self) -> io::Result<usize>
+    {
+        let bytes = &self.buffer[..self.max_chunk_length];
+        let text = match str::from_utf8(bytes)
+        {
+            Ok(text) => text,
+            // cut off in the middle of a character
+            Err(err) if err.error_len().is_none() => str::from_utf8(&bytes[..err.valid_up_to()]).unwrap(),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "stream is not valid UTF-8")),
+        };
+        let after = |(index, c) : (usize, char)| index + c.len_utf8();
+        let point = text.char_indices().rev().find(|&(_, c)| matches!(c, '。' | '．' | '！' | '？' | '!' | '?' | '.')).map(after)
+            .or_else(|| text.char_indices().rev().find(|&(_, c)| c.is_whitespace()).map(after))
+            .unwrap_or(text.len());
+        Ok(point)
+    }
+}
+
+impl<'a, R : BufRead> Iterator for TokenStream<'a, R> {
+    type Item = io::Result<StreamSentence>;
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        loop
+        {
+            let (length, skip) = match self.next_chunk()
+            {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => return None,
+                Err(err) =>
+                {
+                    self.buffer.clear();
+                    self.at_end = true;
+                    return Some(Err(err));
+                }
+            };
+            let offset = self.offset;
+            let chunk : Vec<u8> = self.buffer.drain(..length + skip).take(length).collect();
+            self.offset += length + skip;
+            if chunk.is_empty() || self.is_stripped_away(&chunk)
+            {
+                continue;
+            }
+
+            let result = String::from_utf8(chunk)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream is not valid UTF-8"))
+                .and_then(|text| {
+                    let mut tokens = Vec::new();
+                    let cost = self.dict.tokenize_with_cache(&mut self.cache, &text, &mut tokens)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    for token in &mut tokens
+                    {
+                        token.range = token.range.start + offset..token.range.end + offset;
+                    }
+                    Ok(StreamSentence { offset, text, tokens, cost })
+                });
+            if result.is_err()
+            {
+                self.buffer.clear();
+                self.at_end = true;
+            }
+            return Some(result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::tests::test_dict;
+
+    #[test]
+    fn test_tokenize_stream()
+    {
+        let dict = test_dict();
+        let text = "これを持っていけ\r\n\n飛行機 abc\nこれ";
+        let sentences : Vec<StreamSentence> = dict.tokenize_stream(text.as_bytes()).map(|sentence| sentence.unwrap()).collect();
+        assert_eq!(sentences.len(), 3);
+        assert_eq!(sentences[0].text, "これを持っていけ");
+        assert_eq!(sentences[1].offset, text.find("飛行機").unwrap());
+        assert_eq!(sentences[2].text, "これ");
+        for sentence in &sentences
+        {
+            let (tokens, cost) = dict.tokenize(&sentence.text).unwrap();
+            assert_eq!(sentence.cost, cost);
+            let surfaces : Vec<&str> = sentence.tokens.iter().map(|token| token.get_text(text)).collect();
+            let expected : Vec<&str> = tokens.iter().map(|token| token.get_text(&sentence.text)).collect();
+            assert_eq!(surfaces, expected);
+            assert_eq!(sentence.tokens.iter().map(|token| sentence.token_text(token)).collect::<Vec<_>>(), expected);
+        }
+
+        // a long line gets split up after punctuation or whitespace
+        let text = "これを持っていけ。これを持っていけ abc";
+        let mut stream = dict.tokenize_stream(text.as_bytes());
+        stream.set_max_chunk_length(27);
+        let sentences : Vec<String> = stream.map(|sentence| sentence.unwrap().text).collect();
+        assert_eq!(sentences, vec!("これを持っていけ。", "これを持っていけ ", "abc"));
+
+        // lines and chunks of nothing but spaces are skipped without stopping the stream
+        let text = "これ\n   \nを\n";
+        let sentences : Vec<StreamSentence> = dict.tokenize_stream(text.as_bytes()).map(|sentence| sentence.unwrap()).collect();
+        assert_eq!(sentences.iter().map(|sentence| sentence.text.as_str()).collect::<Vec<_>>(), vec!("これ", "を"));
+        assert_eq!(sentences[1].offset, text.find("を").unwrap());
+        let mut stream = dict.tokenize_stream("こ    れ".as_bytes());
+        stream.set_max_chunk_length(4);
+        let sentences : Vec<String> = stream.map(|sentence| sentence.unwrap().text).collect();
+        assert_eq!(sentences, vec!("こ ", "れ"));
+
+        // unless they come out as whitespace tokens
+        let mut dict = test_dict();
+        dict.set_whitespace_tokens(true);
+        let sentences : Vec<StreamSentence> = dict.tokenize_stream(text.as_bytes()).map(|sentence| sentence.unwrap()).collect();
+        assert_eq!(sentences.len(), 3);
+        assert_eq!(sentences[1].text, "   ");
+        assert_eq!(sentences[1].tokens.len(), 1);
+        assert_eq!(sentences[1].tokens[0].kind, crate::TokenType::Whitespace);
+        assert_eq!(sentences[1].tokens[0].range, 7..10);
+        let dict = test_dict();
+
+        // cut in the middle of a character
+        let mut stream = dict.tokenize_stream("これを".as_bytes());
+        stream.set_max_chunk_length(4);
+        let sentences : Vec<String> = stream.map(|sentence| sentence.unwrap().text).collect();
+        assert_eq!(sentences, vec!("こ", "れ", "を"));
+
+        let mut stream = dict.tokenize_stream(&b"abc\n\xFF\nabc"[..]);
+        assert!(stream.next().unwrap().is_ok());
+        assert_eq!(stream.next().unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(stream.next().is_none());
+    }
+}
@@ -1,8 +1,7 @@
 use crate::HashMap;
 
-use std::io::Read;
-
 use super::file::*;
+use super::error::*;
 
 // for loading
 
@@ -32,6 +31,166 @@ impl CharData {
             always_process : ((data >> 31) & 1) != 0,
         }
     }
+    fn write(self) -> u32
+    {
+        (self.typefield & 0x0003_FFFF)
+        | (self.default_type as u32) << 18
+        | (self.prefix_group_len as u32 & 0xF) << 26
+        | (self.greedy_group as u32) << 30
+        | (self.always_process as u32) << 31
+    }
+}
+
+// for compiling
+
+/// Character categories parsed from the text of a mecab char.def file.
+pub (crate) struct CharDefinition {
+    pub (crate) names : Vec<String>,
+    // None for categories that came from a compiled char.bin without anything in it telling how they behave
+    categories : Vec<Option<CharData>>,
+    // resolved code point ranges, in the order they were given; later ranges win
+    ranges : Vec<(u32, u32, CharData)>,
+}
+
+fn parse_code_point(text : &str) -> Result<u32, &'static str>
+{
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).ok_or("code points must be written in hexadecimal")?;
+    u32::from_str_radix(digits, 16).map_err(|_| "code points must be written in hexadecimal")
+}
+
+// Parses char.def lines on top of the given categories. Definitions of categories that already exist replace them.
+fn parse_char_def_lines(text : &str, mut names : Vec<String>, mut categories : Vec<Option<CharData>>) -> Result<CharDefinition, LoadError>
+{
+    let mut defined_here : Vec<&str> = Vec::new();
+    let mut range_lines : Vec<(usize, u32, u32, Vec<&str>)> = Vec::new();
+    for (index, line) in text.lines().enumerate()
+    {
+        let line_number = index + 1;
+        let error = |message| LoadError::Syntax { file : DictFile::CharDef, line : line_number, message };
+        let line = line.split('#').next().unwrap();
+        let fields : Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty()
+        {
+            continue;
+        }
+        if fields[0].starts_with("0x") || fields[0].starts_with("0X")
+        {
+            if fields.len() < 2
+            {
+                return Err(error("code point range without a category"));
+            }
+            let mut bounds = fields[0].splitn(2, "..");
+            let low = parse_code_point(bounds.next().unwrap()).map_err(error)?;
+            let high = match bounds.next() { Some(high) => parse_code_point(high).map_err(error)?, None => low };
+            if high < low
+            {
+                return Err(error("code point range ends before it starts"));
+            }
+            if high > 0x10FFFF
+            {
+                return Err(error("code point out of range"));
+            }
+            range_lines.push((line_number, low, high, fields[1..].to_vec()));
+        }
+        else
+        {
+            if fields.len() < 4
+            {
+                return Err(error("category definitions need a name, INVOKE, GROUP and LENGTH"));
+            }
+            if defined_here.contains(&fields[0])
+            {
+                return Err(error("category defined more than once"));
+            }
+            defined_here.push(fields[0]);
+            let number = match names.iter().position(|name| name == fields[0])
+            {
+                Some(number) => number,
+                None if names.len() >= 18 => return Err(error("more than 18 categories defined")),
+                None =>
+                {
+                    names.push(fields[0].to_string());
+                    categories.push(None);
+                    names.len() - 1
+                }
+            };
+            let flag = |field : &str| match field { "0" => Ok(false), "1" => Ok(true), _ => Err(error("INVOKE and GROUP must be 0 or 1")) };
+            let length = fields[3].parse::<u8>().map_err(|_| error("LENGTH must be a small number"))?;
+            if length > 0xF
+            {
+                return Err(error("LENGTH must be at most 15"));
+            }
+            categories[number] = Some(CharData {
+                typefield : 1 << number,
+                default_type : number as u8,
+                prefix_group_len : length,
+                greedy_group : flag(fields[2])?,
+                always_process : flag(fields[1])?,
+            });
+        }
+    }
+    
+    let mut ranges = Vec::new();
+    for (line, low, high, range_categories) in range_lines
+    {
+        let error = |message| LoadError::Syntax { file : DictFile::CharDef, line, message };
+        let category = |name : &str| match names.iter().position(|other| other == name)
+        {
+            Some(index) => categories[index].ok_or_else(|| error("category isn't used by any character in char.bin, so it has to be defined again")),
+            None => Err(error("undefined category")),
+        };
+        let mut data = category(range_categories[0])?;
+        for name in &range_categories[1..]
+        {
+            data.typefield |= category(name)?.typefield;
+        }
+        ranges.push((low, high, data));
+    }
+    
+    Ok(CharDefinition { names, categories, ranges })
+}
+
+pub (crate) fn parse_char_def(text : &str) -> Result<CharDefinition, LoadError>
+{
+    let definition = parse_char_def_lines(text, Vec::new(), Vec::new())?;
+    definition.default().ok_or(LoadError::Invalid { file : DictFile::CharDef, message : "the DEFAULT category must be defined" })?;
+    Ok(definition)
+}
+
+impl CharDefinition {
+    fn default(&self) -> Option<CharData>
+    {
+        self.names.iter().position(|name| name == "DEFAULT").and_then(|index| self.categories[index])
+    }
+    /// Serializes the categories in the format load_char_bin reads.
+    ///
+    /// char.bin only has room for code points below U+FFFF, so ranges past that are left out.
+    pub (crate) fn to_char_bin(&self) -> Vec<u8>
+    {
+        let mut table = vec!(self.default().unwrap(); 0xFFFF);
+        for &(low, high, data) in &self.ranges
+        {
+            for code_point in low..=std::cmp::min(high, 0xFFFE)
+            {
+                table[code_point as usize] = data;
+            }
+        }
+        
+        let mut out = Vec::with_capacity(4 + self.names.len() * 0x20 + table.len() * 4);
+        out.extend_from_slice(&(self.names.len() as u32).to_le_bytes());
+        for name in &self.names
+        {
+            let mut buffer = [0u8; 0x20];
+            let length = std::cmp::min(name.len(), 0x1F);
+            buffer[..length].copy_from_slice(&name.as_bytes()[..length]);
+            out.extend_from_slice(&buffer);
+        }
+        for data in table
+        {
+            out.extend_from_slice(&data.write().to_le_bytes());
+        }
+        out
+    }
 }
 
 // for usage
@@ -78,7 +237,7 @@ impl TypeData {
     {
         if data.default_type as usize >= names.len()
         {
-            return Err("invalid chars.bin file");
+            return Err("character has a category that doesn't exist");
         }
         Ok(TypeData {
             name : names[data.default_type as usize].clone(),
@@ -91,76 +250,244 @@ impl TypeData {
 }
 
 pub (crate) struct UnkChar {
+    pub (crate) names : Vec<String>,
     types : HashMap<u8, TypeData>,
-    data : Vec<CharType>
+    // one entry per code point below U+FFFF, like char.bin
+    data : Vec<CharType>,
+    // ranges of code points past the end of data, from char.def; later ranges win
+    extended : Vec<(u32, u32, CharType)>,
 }
 
 impl UnkChar {
-    pub (crate) fn get_type(&'_ self, c : char) -> &'_ TypeData
+    fn new(names : Vec<String>) -> UnkChar
     {
-        if (c as u32) < 0xFFFF
-        {
-            &self.types[&self.data[c as usize].default_type]
+        UnkChar {
+            names,
+            types : HashMap::new(),
+            data : Vec::new(),
+            extended : Vec::new(),
         }
-        else
+    }
+    fn char_type(&self, c : char) -> Option<CharType>
+    {
+        let code_point = c as u32;
+        match self.data.get(code_point as usize)
         {
-            &self.types[&0]
+            Some(char_type) => Some(*char_type),
+            None => self.extended.iter().rev().find(|(low, high, _)| (*low..=*high).contains(&code_point)).map(|(_, _, char_type)| *char_type),
         }
     }
-    pub (crate) fn has_type(&self, c : char, ctype : u8) -> bool
+    pub (crate) fn get_type(&'_ self, c : char) -> &'_ TypeData
     {
-        if (c as u32) < 0xFFFF
+        match self.char_type(c)
         {
-            self.data[c as usize].has_type(ctype)
+            Some(char_type) => &self.types[&char_type.default_type],
+            None => &self.types[&0],
         }
-        else
+    }
+    pub (crate) fn has_type(&self, c : char, ctype : u8) -> bool
+    {
+        match self.char_type(c)
         {
-            ctype == 0
+            Some(char_type) => char_type.has_type(ctype),
+            None => ctype == 0,
         }
     }
     pub (crate) fn always_process(&self, c : char) -> bool
     {
         self.get_type(c).always_process
     }
+    fn push(&mut self, data : CharData) -> Result<(), &'static str>
+    {
+        if !self.types.contains_key(&data.default_type)
+        {
+            self.types.insert(data.default_type, TypeData::from(data, &self.names)?);
+        }
+        self.data.push(CharType::from(data));
+        Ok(())
+    }
+    // what's known about each category, for parsing char.def lines on top of this
+    fn categories(&self) -> Vec<Option<CharData>>
+    {
+        (0..self.names.len()).map(|number| self.types.get(&(number as u8)).map(|data| CharData {
+            typefield : 1 << number,
+            default_type : number as u8,
+            prefix_group_len : data.prefix_group_len,
+            greedy_group : data.greedy_group,
+            always_process : data.always_process,
+        })).collect()
+    }
+    fn set_range(&mut self, low : u32, high : u32, data : CharData) -> Result<(), &'static str>
+    {
+        self.types.insert(data.default_type, TypeData::from(data, &self.names)?);
+        let table_end = self.data.len() as u32;
+        for code_point in low..std::cmp::min(high + 1, table_end)
+        {
+            self.data[code_point as usize] = CharType::from(data);
+        }
+        if high >= table_end
+        {
+            self.extended.push((std::cmp::max(low, table_end), high, CharType::from(data)));
+        }
+        Ok(())
+    }
+    // applies the categories and ranges from parsed char.def lines
+    fn apply(&mut self, definition : CharDefinition) -> Result<(), LoadError>
+    {
+        let error = |message| LoadError::Invalid { file : DictFile::CharDef, message };
+        self.names = definition.names;
+        for data in definition.categories.into_iter().flatten()
+        {
+            self.types.insert(data.default_type, TypeData::from(data, &self.names).map_err(error)?);
+        }
+        for (low, high, data) in definition.ranges
+        {
+            self.set_range(low, high, data).map_err(error)?;
+        }
+        Ok(())
+    }
+    /// Parses the text of (part of) a char.def file on top of the current categories. See [`crate::Dict::apply_char_def`].
+    pub (crate) fn apply_char_def(&mut self, text : &str) -> Result<(), LoadError>
+    {
+        let definition = parse_char_def_lines(text, self.names.clone(), self.categories())?;
+        self.apply(definition)
+    }
 }
 
-pub (crate) fn load_char_bin<T : Read>(file : &mut T) -> Result<UnkChar, &'static str>
+pub (crate) fn load_char_bin(data : &[u8]) -> Result<UnkChar, LoadError>
 {
-    let num_types = read_u32(file)?;
+    let mut file = FileReader::new(data, DictFile::CharBin);
+    let num_types = file.u32()?;
+    file.set_section(Section::CategoryNames);
     let mut type_names = Vec::new();
     for _ in 0..num_types
     {
-        type_names.push(read_nstr(file, 0x20)?);
+        type_names.push(file.nstr(0x20)?);
     }
-    let mut unk_chars = UnkChar {
-        types : HashMap::new(),
-        data : Vec::new()
-    };
+    let mut unk_chars = UnkChar::new(type_names);
+    file.set_section(Section::CharacterTable);
     for _ in 0..0xFFFF
     {
-        let bitfield = read_u32(file)?;
-        let data = CharData::read(bitfield);
-        unk_chars.types.entry(data.default_type).or_insert(TypeData::from(data, &type_names)?);
-        unk_chars.data.push(CharType::from(data));
+        let bitfield = file.u32()?;
+        unk_chars.push(CharData::read(bitfield)).map_err(|message| file.malformed(message))?;
     }
     Ok(unk_chars)
 }
 
+/// Loads the text of a mecab char.def file directly, without compiling it to char.bin first.
+pub (crate) fn load_char_def(text : &str) -> Result<UnkChar, LoadError>
+{
+    let definition = parse_char_def(text)?;
+    let mut unk_chars = UnkChar::new(definition.names.clone());
+    let default = definition.default().unwrap();
+    for _ in 0..0xFFFF
+    {
+        unk_chars.push(default).map_err(|message| LoadError::Invalid { file : DictFile::CharDef, message })?;
+    }
+    unk_chars.apply(definition)?;
+    Ok(unk_chars)
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
     use super::*;
     use crate::dart;
     use crate::blob::Blob;
     
+    #[test]
+    fn test_char_def_compile()
+    {
+        let char_def = "
+            DEFAULT 0 1 0  # DEFAULT is a mandatory category!
+            SPACE   0 1 0
+            KANJI   0 0 2
+            KANJINUMERIC 1 1 0
+            
+            0x0020 SPACE
+            0x4E00..0x9FFF KANJI
+            0x4E00 KANJINUMERIC KANJI
+        ";
+        let definition = parse_char_def(char_def).unwrap();
+        assert_eq!(definition.names, vec!("DEFAULT", "SPACE", "KANJI", "KANJINUMERIC"));
+        let unk_chars = load_char_bin(&definition.to_char_bin()).unwrap();
+        assert_eq!(unk_chars.get_type('a').name, "DEFAULT");
+        assert_eq!(unk_chars.get_type(' ').name, "SPACE");
+        assert_eq!(unk_chars.get_type('噛').name, "KANJI");
+        assert_eq!(unk_chars.get_type('噛').prefix_group_len, 2);
+        assert_eq!(unk_chars.get_type('一').name, "KANJINUMERIC");
+        assert!(unk_chars.always_process('一'));
+        assert!(unk_chars.has_type('一', 2));
+        assert!(!unk_chars.has_type('噛', 3));
+        
+        let loaded = load_char_def(char_def).unwrap();
+        assert_eq!(loaded.names, unk_chars.names);
+        for c in "a 噛一".chars()
+        {
+            assert_eq!(loaded.get_type(c).name, unk_chars.get_type(c).name);
+            assert_eq!(loaded.always_process(c), unk_chars.always_process(c));
+        }
+        
+        assert!(matches!(parse_char_def("SPACE 0 1 0\n0x0020 SPACE"), Err(LoadError::Invalid { file : DictFile::CharDef, .. })));
+        assert!(matches!(parse_char_def("DEFAULT 0 1 0\n0x0020 SPACE"), Err(LoadError::Syntax { file : DictFile::CharDef, line : 2, .. })));
+        assert!(matches!(parse_char_def("DEFAULT 0 1 0\n\nSPACE 0 2 0"), Err(LoadError::Syntax { line : 3, .. })));
+        
+        let char_bin = definition.to_char_bin();
+        match load_char_bin(&char_bin[..char_bin.len() - 2])
+        {
+            Err(LoadError::Io { file : DictFile::CharBin, section : Section::CharacterTable, offset, .. }) => assert_eq!(offset as usize, char_bin.len() - 4),
+            _ => panic!("expected an IO error in the character table"),
+        }
+    }
+    
+    #[test]
+    fn test_supplementary_planes()
+    {
+        let char_def = "
+            DEFAULT 0 1 0
+            KANJI   0 0 2
+            EMOJI   1 1 0
+            
+            0x4E00..0x9FFF KANJI
+            0x20000..0x2FFFF KANJI
+            0xFFFF EMOJI
+        ";
+        let loaded = load_char_def(char_def).unwrap();
+        assert_eq!(loaded.get_type('𠮷').name, "KANJI");
+        assert!(loaded.has_type('𠮷', 1));
+        assert_eq!(loaded.get_type('\u{FFFF}').name, "EMOJI");
+        assert_eq!(loaded.get_type('😀').name, "DEFAULT");
+        assert!(loaded.has_type('😀', 0));
+        
+        // char.bin can't hold them, but they can be put back afterwards
+        let mut unk_chars = load_char_bin(&parse_char_def(char_def).unwrap().to_char_bin()).unwrap();
+        assert_eq!(unk_chars.get_type('𠮷').name, "DEFAULT");
+        assert!(unk_chars.apply_char_def("0x1F300..0x1FAFF EMOJI").is_err());
+        unk_chars.apply_char_def("EMOJI 1 1 0\n0x20000..0x2FFFF KANJI\n0x1F300..0x1FAFF EMOJI").unwrap();
+        assert_eq!(unk_chars.get_type('𠮷').name, "KANJI");
+        assert_eq!(unk_chars.get_type('😀').name, "EMOJI");
+        assert!(unk_chars.get_type('😀').always_process);
+        assert_eq!(unk_chars.get_type('噛').name, "KANJI");
+        
+        // new categories and overrides of old ones
+        unk_chars.apply_char_def("HENTAIGANA 0 1 0\nKANJI 0 1 3\n0x1B000..0x1B16F HENTAIGANA\n0x1F600 KANJI").unwrap();
+        assert_eq!(unk_chars.names.last().unwrap(), "HENTAIGANA");
+        assert_eq!(unk_chars.get_type('𛁁').name, "HENTAIGANA");
+        assert_eq!(unk_chars.get_type('😀').name, "KANJI");
+        assert_eq!(unk_chars.get_type('😁').name, "EMOJI");
+        assert_eq!(unk_chars.get_type('噛').prefix_group_len, 3);
+        
+        assert!(matches!(unk_chars.apply_char_def("0x1F600 NONEXISTENT"), Err(LoadError::Syntax { file : DictFile::CharDef, line : 1, .. })));
+        assert!(matches!(unk_chars.apply_char_def("0x110000 KANJI"), Err(LoadError::Syntax { .. })));
+    }
+    
     #[test]
     fn test_unkchar_load()
     {
         let unkdic = Blob::open("data/unk.dic").unwrap();
         let unkdef = Blob::open("data/char.bin").unwrap();
         
-        dart::load_mecab_dart_file(unkdic).unwrap();
-        load_char_bin(&mut Cursor::new(unkdef)).unwrap();
+        dart::load_mecab_dart_file(unkdic, DictFile::UnkDic, false).unwrap();
+        load_char_bin(&unkdef).unwrap();
     }
 }
 
@@ -2,67 +2,154 @@ use std::io::BufRead;
 use std::io::Read;
 
 use crate::HashMap;
-use crate::HashSet;
 
 use crate::FormatToken;
+use crate::dart::DartDict;
+use crate::dart::Tokens;
+use crate::error::*;
+
+/// A word in a user dictionary. See [`crate::Dict::add_user_entry`].
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq, Eq)]
+pub struct UserEntry {
+    pub surface : String,
+    pub left_context : u16,
+    pub right_context : u16,
+    pub cost : i64,
+    /// The feature string, normally comma-separated fields in the same format as the main dictionary's.
+    pub feature : String,
+}
 
 #[derive(Debug)]
 pub (crate) struct UserDict {
     pub(crate) dict: HashMap<String, Vec<FormatToken>>,
-    pub(crate) contains_longer: HashSet<String>,
+    // number of surfaces in dict that each string is a proper prefix of
+    contains_longer: HashMap<String, usize>,
+    // feature strings are never removed, so that the feature offsets of tokens that were already handed out stay valid
     pub(crate) features: Vec<String>,
+    // the surface each entry is listed under, by entry ID (original_id)
+    surfaces: HashMap<u32, String>,
+    next_id: u32,
 }
 
 impl UserDict {
-    pub (crate) fn load<T : Read + BufRead>(file : &mut T) -> Result<UserDict, &'static str>
+    pub (crate) fn new() -> UserDict
+    {
+        UserDict { dict : HashMap::new(), contains_longer : HashMap::new(), features : Vec::new(), surfaces : HashMap::new(), next_id : 0 }
+    }
+    pub (crate) fn load<T : Read + BufRead>(file : &mut T) -> Result<UserDict, LoadError>
     {
-        let mut dict : HashMap<String, Vec<FormatToken>> = HashMap::new();
-        let mut contains_longer = HashSet::new();
-        let mut features = Vec::new();
+        let mut user_dict = UserDict::new();
         for (i, line) in file.lines().enumerate()
         {
-            let line = line.or_else(|_| Err("IO error"))?;
+            let error = |message| LoadError::Syntax { file : DictFile::UserDic, line : i + 1, message };
+            let line = line.map_err(|_| error("not valid UTF-8"))?;
+            user_dict.next_id = i as u32 + 1;
             let parts : Vec<&str> = line.splitn(5, ',').collect();
             if parts.len() != 5
             {
                 continue;
             }
-            let surface = parts[0].to_string();
-            let left_context = parts[1].parse::<u16>().or_else(|_| Err("numeric parse error"))?;
-            let right_context = parts[2].parse::<u16>().or_else(|_| Err("numeric parse error"))?;
-            let cost = parts[3].parse::<i64>().or_else(|_| Err("numeric parse error"))?;
-            let feature = parts[4].to_string();
-            let token = FormatToken
-            { left_context,
-              right_context,
-              pos : 0,
-              cost,
-              original_id : i as u32,
-              feature_offset : i as u32
+            let entry = UserEntry {
+                surface : parts[0].to_string(),
+                left_context : parts[1].parse::<u16>().map_err(|_| error("invalid left context ID"))?,
+                right_context : parts[2].parse::<u16>().map_err(|_| error("invalid right context ID"))?,
+                cost : parts[3].parse::<i64>().map_err(|_| error("invalid cost"))?,
+                feature : parts[4].to_string(),
             };
-            if let Some(list) = dict.get_mut(&surface)
-            {
-                list.push(token);
-            }
-            else
+            user_dict.insert(i as u32, entry);
+        }
+        Ok(user_dict)
+    }
+    
+    fn prefixes(surface : &str) -> impl Iterator<Item = &str>
+    {
+        surface.char_indices().skip(1).map(move |(i, _)| &surface[..i])
+    }
+    fn insert(&mut self, id : u32, entry : UserEntry)
+    {
+        let token = FormatToken
+        { left_context : entry.left_context,
+          right_context : entry.right_context,
+          pos : 0,
+          cost : entry.cost,
+          original_id : id,
+          feature_offset : self.features.len() as u32
+        };
+        self.features.push(entry.feature);
+        if let Some(list) = self.dict.get_mut(&entry.surface)
+        {
+            list.push(token);
+        }
+        else
+        {
+            for prefix in Self::prefixes(&entry.surface)
             {
-                dict.insert(surface.clone(), vec!(token));
+                *self.contains_longer.entry(prefix.to_string()).or_insert(0) += 1;
             }
-            for (i, _) in surface.char_indices()
+            self.dict.insert(entry.surface.clone(), vec!(token));
+        }
+        self.surfaces.insert(id, entry.surface);
+    }
+    /// Adds an entry, returning its ID.
+    pub (crate) fn add(&mut self, entry : UserEntry) -> u32
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.insert(id, entry);
+        id
+    }
+    pub (crate) fn remove(&mut self, id : u32) -> bool
+    {
+        let surface = match self.surfaces.remove(&id)
+        {
+            Some(surface) => surface,
+            None => return false
+        };
+        let list = self.dict.get_mut(&surface).unwrap();
+        list.retain(|token| token.original_id != id);
+        if list.is_empty()
+        {
+            self.dict.remove(&surface);
+            for prefix in Self::prefixes(&surface)
             {
-                if i > 0
+                let count = self.contains_longer.get_mut(prefix).unwrap();
+                *count -= 1;
+                if *count == 0
                 {
-                    contains_longer.insert(surface[0..i].to_string());
+                    self.contains_longer.remove(prefix);
                 }
             }
-            features.push(feature);
         }
-        Ok(UserDict { dict, contains_longer, features })
+        true
+    }
+    /// Replaces an entry, keeping its ID. Its token gets a new feature offset, but the old one stays valid.
+    pub (crate) fn update(&mut self, id : u32, entry : UserEntry) -> bool
+    {
+        if !self.remove(id)
+        {
+            return false;
+        }
+        self.insert(id, entry);
+        true
+    }
+    pub (crate) fn entry(&self, id : u32) -> Option<UserEntry>
+    {
+        let surface = self.surfaces.get(&id)?;
+        let token = self.dict[surface].iter().find(|token| token.original_id == id)?;
+        Some(UserEntry {
+            surface : surface.clone(),
+            left_context : token.left_context,
+            right_context : token.right_context,
+            cost : token.cost,
+            feature : self.features[token.feature_offset as usize].clone(),
+        })
     }
     
     pub (crate) fn may_contain(&self, find : &str) -> bool
     {
-        self.contains_longer.contains(find) || self.dict.contains_key(find)
+        self.contains_longer.contains_key(find) || self.dict.contains_key(find)
     }
     pub (crate) fn dic_get<'a>(&'a self, find : &str) -> Option<&'a Vec<FormatToken>>
     {
@@ -74,11 +161,55 @@ impl UserDict {
     }
 }
 
+/// A user dictionary, either loaded from CSV or compiled by mecab-dict-index.
+pub (crate) enum UserDictionary {
+    Text(UserDict),
+    Compiled(DartDict),
+}
+
+impl UserDictionary {
+    /// Every prefix of `text` in the dictionary, shortest first, as its length in bytes and its tokens.
+    pub (crate) fn common_prefixes<'a>(&'a self, text : &str) -> Vec<(usize, Tokens<'a>)>
+    {
+        match self
+        {
+            UserDictionary::Text(dict) =>
+            {
+                let mut matches = Vec::new();
+                for (index, c) in text.char_indices()
+                {
+                    let end = index + c.len_utf8();
+                    if !dict.may_contain(&text[..end])
+                    {
+                        break;
+                    }
+                    if let Some(tokens) = dict.dic_get(&text[..end])
+                    {
+                        matches.push((end, Tokens::Loaded(tokens.iter())));
+                    }
+                }
+                matches
+            }
+            UserDictionary::Compiled(dict) => dict.common_prefixes(text).collect(),
+        }
+    }
+    pub (crate) fn feature_get(&self, offset : u32) -> &str
+    {
+        match self
+        {
+            UserDictionary::Text(dict) => dict.feature_get(offset),
+            UserDictionary::Compiled(dict) => dict.feature_get(offset),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::File;
     use std::io::BufReader;
     use super::*;
+    use crate::Blob;
+    use crate::TokenType;
     
     #[test]
     fn test_unkchar_load()
@@ -86,5 +217,100 @@ mod tests {
         let mut usrdic = BufReader::new(File::open("data/userdict.csv").unwrap());
         UserDict::load(&mut usrdic).unwrap();
     }
+    
+    #[test]
+    fn test_user_entries()
+    {
+        let mut dict = crate::compiler::tests::test_dict();
+        let text = "飛行機";
+        let surfaces = |dict : &crate::Dict| dict.tokenize(text).unwrap().0.iter().map(|token| (token.get_text(text).to_string(), token.kind)).collect::<Vec<_>>();
+        assert_eq!(surfaces(&dict).len(), 2);
+        
+        let entry = |surface : &str, cost, feature : &str| UserEntry { surface : surface.to_string(), left_context : 1, right_context : 1, cost, feature : feature.to_string() };
+        assert!(dict.add_user_entry(0, entry("飛行機", -1000, "")).is_err());
+        let words = dict.create_user_dictionary("words").unwrap();
+        let id = dict.add_user_entry(words, entry("飛行機", -1000, "名詞,固有名詞")).unwrap();
+        let other = dict.add_user_entry(words, entry("飛行船", -1000, "名詞,普通名詞")).unwrap();
+        let (tokens, _) = dict.tokenize(text).unwrap();
+        assert_eq!(surfaces(&dict), vec!(("飛行機".to_string(), crate::TokenType::User(words))));
+        assert_eq!(tokens[0].original_id, id);
+        assert_eq!(tokens[0].get_feature(&dict), "名詞,固有名詞");
+        assert_eq!(dict.user_entry(words, id), Some(entry("飛行機", -1000, "名詞,固有名詞")));
+        
+        // the old token's feature stays readable after an update
+        assert!(dict.update_user_entry(words, id, entry("飛行機", -2000, "名詞,一般")).unwrap());
+        assert_eq!(tokens[0].get_feature(&dict), "名詞,固有名詞");
+        assert_eq!(dict.tokenize(text).unwrap().0[0].get_feature(&dict), "名詞,一般");
+        
+        assert!(dict.remove_user_entry(words, id));
+        assert!(!dict.remove_user_entry(words, id));
+        assert!(!dict.update_user_entry(words, id, entry("飛行機", 0, "")).unwrap());
+        assert_eq!(surfaces(&dict).len(), 2);
+        assert!(dict.user_entry(words, other).is_some());
+        
+        assert!(dict.add_user_entry(words, entry("", 0, "")).is_err());
+        assert!(dict.add_user_entry(words, UserEntry { left_context : 100, ..entry("a", 0, "") }).is_err());
+    }
+    
+    #[test]
+    fn test_stacked_user_dictionaries()
+    {
+        let mut dict = crate::compiler::tests::test_dict();
+        let company = dict.add_user_dictionary("company", crate::Blob::new("飛行機,1,1,-1000,company\n飛行,1,1,-1000,company")).unwrap();
+        let project = dict.add_user_dictionary("project", crate::Blob::new("飛行機,1,1,-1000,project")).unwrap();
+        assert!(dict.add_user_dictionary("company", crate::Blob::new("")).is_err());
+        assert_eq!((company, project), (0, 1));
+        assert_eq!(dict.user_dictionary_name(project), Some("project"));
+        assert_eq!(dict.user_dictionary_index("company"), Some(company));
+        
+        // the later dictionary hides the earlier one's entries for the same surface, but not for others
+        let lattice = dict.build_lattice("飛行機");
+        let user_nodes : Vec<(&str, crate::TokenType)> = lattice.nodes.iter()
+            .filter(|node| matches!(node.kind, crate::TokenType::User(_)))
+            .map(|node| (dict.read_feature_string_by_source(node.kind, node.feature_offset), node.kind))
+            .collect();
+        assert_eq!(user_nodes, vec!(("company", crate::TokenType::User(company)), ("project", crate::TokenType::User(project))));
+        let (tokens, _) = dict.tokenize("飛行機").unwrap();
+        assert_eq!(tokens[0].kind, crate::TokenType::User(project));
+        assert_eq!(tokens[0].get_feature(&dict), "project");
+        
+        dict.load_user_dictionary(crate::Blob::new("機,1,1,0,user")).unwrap();
+        assert_eq!(dict.user_dictionary_name(0), Some("user"));
+        assert_eq!(dict.user_dictionary_name(1), None);
+    }
+    
+    #[test]
+    fn test_prefix_counts()
+    {
+        let mut user_dict = UserDict::load(&mut "あいう,1,1,0,a\nbroken line\nあい,1,1,0,b\n".as_bytes()).unwrap();
+        assert_eq!(user_dict.feature_get(user_dict.dic_get("あい").unwrap()[0].feature_offset), "b");
+        assert!(user_dict.may_contain("あ"));
+        assert!(user_dict.remove(0));
+        assert!(user_dict.may_contain("あ"));
+        assert!(!user_dict.may_contain("あいう"));
+        assert!(user_dict.remove(2));
+        assert!(!user_dict.may_contain("あ"));
+        assert_eq!(user_dict.add(UserEntry { surface : "う".to_string(), left_context : 1, right_context : 1, cost : 0, feature : String::new() }), 3);
+    }
+    
+    #[test]
+    fn test_load_compiled_user_dictionary()
+    {
+        use crate::compiler::tests::*;
+        let compile_user = |dict_type, left_contexts, right_contexts| compile_user_dict("飛行機,1,1,100,名詞,固有名詞,一般,*,*,*,ヒコウキ,飛行機,飛行機", dict_type, left_contexts, right_contexts);
+        
+        let mut dict = test_dict();
+        dict.load_user_dictionary(Blob::new(compile_user(1, 4, 4))).unwrap();
+        
+        let text = "飛行機を";
+        let (tokens, _) = dict.tokenize(text).unwrap();
+        let surfaces : Vec<&str> = tokens.iter().map(|token| token.get_text(text)).collect();
+        assert_eq!(surfaces, vec!("飛行機", "を"));
+        assert_eq!(tokens[0].kind, TokenType::User(0));
+        assert_eq!(tokens[0].get_feature(&dict), "名詞,固有名詞,一般,*,*,*,ヒコウキ,飛行機,飛行機");
+        assert_eq!(tokens[1].kind, TokenType::Normal);
+        
+        assert!(dict.load_user_dictionary(Blob::new(compile_user(0, 4, 4))).is_err());
+        assert!(dict.load_user_dictionary(Blob::new(compile_user(1, 5, 4))).is_err());
+    }
 }
-
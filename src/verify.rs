@@ -0,0 +1,171 @@
+use std::fmt;
+
+use super::dart::DartDict;
+use super::userdict::UserDictionary;
+use super::Dict;
+use super::DictFile;
+use super::FormatToken;
+
+/// A consistency problem found by [`Dict::verify`].
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq, Eq)]
+pub enum Problem {
+    /// A surface in the trie points at tokens past the end of the token table.
+    TokenRangeOutOfBounds { file : DictFile, surface : String, first : usize, end : usize, token_count : usize },
+    /// A token's feature offset is past the end of the feature pile, doesn't point at the start of a character, or points at a string that isn't NUL-terminated.
+    BrokenFeatureOffset { file : DictFile, token : usize, offset : u32 },
+    /// A token's context IDs don't fit into the connection matrix.
+    ContextOutOfRange { file : DictFile, token : usize, left_context : u16, right_context : u16 },
+    /// A character category has no tokens in unk.dic.
+    MissingUnknownCategory { name : String },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, fmt : &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Problem::TokenRangeOutOfBounds { file, surface, first, end, token_count } =>
+                write!(fmt, "{}: surface {:?} points at tokens {}..{}, but there are only {} tokens", file, surface, first, end, token_count),
+            Problem::BrokenFeatureOffset { file, token, offset } =>
+                write!(fmt, "{}: token {} has a feature offset (0x{:X}) that doesn't point at a feature string", file, token, offset),
+            Problem::ContextOutOfRange { file, token, left_context, right_context } =>
+                write!(fmt, "{}: token {} has context IDs ({}, {}) that are out of range of the connection matrix", file, token, left_context, right_context),
+            Problem::MissingUnknownCategory { name } =>
+                write!(fmt, "unk.dic: no tokens for the character category {:?}", name),
+        }
+    }
+}
+
+impl Dict {
+    /// Checks that the loaded dictionary files are consistent with each other, so that tokenizing with them can't panic.
+    ///
+    /// Walks every surface and token of sys.dic, unk.dic and every user dictionary. Returns every problem found, or nothing if the dictionary is fine.
+    pub fn verify(&self) -> Vec<Problem>
+    {
+        let mut problems = Vec::new();
+        self.verify_dart_dict(&self.sys_dic, DictFile::SysDic, &mut problems);
+        self.verify_dart_dict(&self.unk_dic, DictFile::UnkDic, &mut problems);
+        for (_, user_dic) in &self.user_dics
+        {
+            match user_dic
+            {
+                UserDictionary::Compiled(user_dic) => self.verify_dart_dict(user_dic, DictFile::UserDic, &mut problems),
+                UserDictionary::Text(user_dic) =>
+                {
+                    for token in user_dic.dict.values().flatten()
+                    {
+                        self.verify_contexts(token, DictFile::UserDic, &mut problems);
+                    }
+                }
+            }
+        }
+        
+        let mut names : Vec<&str> = self.unk_data.names.iter().map(|name| name.as_str()).collect();
+        if !names.contains(&"DEFAULT")
+        {
+            names.push("DEFAULT");
+        }
+        for name in names
+        {
+            if self.unk_dic.dic_get(name).map(|tokens| tokens.len() == 0).unwrap_or(true)
+            {
+                problems.push(Problem::MissingUnknownCategory { name : name.to_string() });
+            }
+        }
+        problems
+    }
+    
+    fn verify_dart_dict(&self, dict : &DartDict, file : DictFile, problems : &mut Vec<Problem>)
+    {
+        let token_count = dict.token_count();
+        let mut surfaces : Vec<(String, std::ops::Range<usize>)> = dict.entries().into_iter().filter(|(_, range)| range.end > token_count).collect();
+        surfaces.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (surface, range) in surfaces
+        {
+            problems.push(Problem::TokenRangeOutOfBounds { file, surface, first : range.start, end : range.end, token_count });
+        }
+        
+        let features = dict.feature_bytes();
+        for (index, token) in dict.tokens().enumerate()
+        {
+            let offset = token.feature_offset as usize;
+            let is_valid = match features.get(offset..)
+            {
+                Some(rest) => (rest.first().map(|&byte| (byte as i8) >= -0x40).unwrap_or(false)) && rest.contains(&0),
+                None => false
+            };
+            if !is_valid
+            {
+                problems.push(Problem::BrokenFeatureOffset { file, token : index, offset : token.feature_offset });
+            }
+            self.verify_contexts(&token, file, problems);
+        }
+    }
+    
+    fn verify_contexts(&self, token : &FormatToken, file : DictFile, problems : &mut Vec<Problem>)
+    {
+        // the right context of the token on the left picks the column and the left context of the token on the right picks the row
+        if token.right_context >= self.left_edges || token.left_context >= self.right_edges
+        {
+            problems.push(Problem::ContextOutOfRange {
+                file,
+                token : token.original_id as usize,
+                left_context : token.left_context,
+                right_context : token.right_context,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Blob;
+    use crate::CompiledDictionary;
+    use crate::compiler::tests::*;
+    use crate::file::read_u32;
+
+    fn load(compiled : CompiledDictionary, sys_dic : Vec<u8>) -> Dict
+    {
+        Dict::load(Blob::new(sys_dic), Blob::new(compiled.unk_dic), Blob::new(compiled.matrix), Blob::new(compiled.char_bin)).unwrap()
+    }
+
+    #[test]
+    fn test_verify()
+    {
+        assert_eq!(test_dict().verify(), vec!());
+
+        let compiled = CompiledDictionary::compile(&[LEXICON], MATRIX_DEF, CHAR_DEF, UNK_DEF).unwrap();
+        let link_bytes = read_u32(&mut &compiled.sys_dic[0x18..]).unwrap() as usize;
+        let token_bytes = read_u32(&mut &compiled.sys_dic[0x1C..]).unwrap() as usize;
+        let tokens_start = 0x48 + link_bytes;
+
+        // point the first token's feature somewhere past the end, and give the second one a context that doesn't exist
+        let mut sys_dic = compiled.sys_dic.clone();
+        sys_dic[tokens_start + 8..tokens_start + 12].copy_from_slice(&0xFFFFu32.to_le_bytes());
+        sys_dic[tokens_start + 16..tokens_start + 18].copy_from_slice(&9u16.to_le_bytes());
+        let dict = load(compiled, sys_dic);
+        let problems = dict.verify();
+        assert_eq!(problems.len(), 2);
+        assert!(matches!(problems[0], Problem::BrokenFeatureOffset { file : DictFile::SysDic, token : 0, offset : 0xFFFF }));
+        assert!(matches!(problems[1], Problem::ContextOutOfRange { file : DictFile::SysDic, token : 1, left_context : 9, .. }));
+
+        // drop the last token, so the surface that uses it points past the end of the token table
+        let compiled = CompiledDictionary::compile(&[LEXICON], MATRIX_DEF, CHAR_DEF, UNK_DEF).unwrap();
+        let mut sys_dic = compiled.sys_dic.clone();
+        sys_dic.drain(tokens_start + token_bytes - 16..tokens_start + token_bytes);
+        sys_dic[0x1C..0x20].copy_from_slice(&(token_bytes as u32 - 16).to_le_bytes());
+        let dict = load(compiled, sys_dic);
+        let problems = dict.verify();
+        assert_eq!(problems.len(), 1);
+        assert!(matches!(&problems[0], Problem::TokenRangeOutOfBounds { file : DictFile::SysDic, end : 10, token_count : 9, .. }));
+
+        let compiled = CompiledDictionary::compile(&[LEXICON], MATRIX_DEF, CHAR_DEF, "DEFAULT,1,1,5000,補助記号,一般,*,*,*,*").unwrap();
+        let problems = compiled.load().unwrap().verify();
+        let names : Vec<String> = problems.iter().map(|problem| match problem { Problem::MissingUnknownCategory { name } => name.clone(), _ => panic!() }).collect();
+        assert_eq!(names, vec!("SPACE", "KANJI", "ALPHA"));
+        assert_eq!(problems[0].to_string(), "unk.dic: no tokens for the character category \"SPACE\"");
+    }
+}